@@ -0,0 +1,57 @@
+create index request_method on requests(json_extract(har, '$.entries[0].request.method'));
+create index request_status on requests(json_extract(har, '$.entries[0].response.status'));
+
+-- Columns extracted from the HAR of each request so they can be filtered on without repeating
+-- JSON expressions in every query
+create view request_summaries as
+select
+    request_id,
+    created_at,
+    har,
+    method,
+    url,
+    status,
+    duration,
+    case
+        when instr(path_and_query, '?') > 0 then substr(path_and_query, 1, instr(path_and_query, '?') - 1)
+        else path_and_query
+    end as path,
+    coalesce(url_host, host_header) as host
+from (
+    select
+        *,
+        case
+            when authority_and_path is null then url
+            when instr(authority_and_path, '/') > 0 then substr(authority_and_path, instr(authority_and_path, '/'))
+            else '/'
+        end as path_and_query,
+        case
+            when authority_and_path is null then null
+            when instr(authority_and_path, '/') > 0 then substr(authority_and_path, 1, instr(authority_and_path, '/') - 1)
+            else authority_and_path
+        end as url_host
+    from (
+        select
+            *,
+            case
+                when instr(url, '://') > 0 then substr(url, instr(url, '://') + 3)
+                else null
+            end as authority_and_path
+        from (
+            select
+                request_id,
+                created_at,
+                har,
+                json_extract(har, '$.entries[0].request.method') as method,
+                json_extract(har, '$.entries[0].request.url') as url,
+                json_extract(har, '$.entries[0].response.status') as status,
+                json_extract(har, '$.entries[0].time') as duration,
+                (
+                    select json_extract(h.value, '$.value')
+                    from json_each(har, '$.entries[0].request.headers') as h
+                    where lower(json_extract(h.value, '$.name')) in ('host', ':authority')
+                ) as host_header
+            from requests
+        )
+    )
+);
@@ -0,0 +1,105 @@
+-- Columns extracted from the HAR of each request when it is stored, so requests can be filtered
+-- and aggregated without parsing JSON. The full HAR is still stored for export
+alter table requests add column method text;
+alter table requests add column url text;
+alter table requests add column scheme text;
+alter table requests add column host text;
+alter table requests add column path text;
+alter table requests add column query text;
+alter table requests add column status integer;
+alter table requests add column duration real;
+alter table requests add column request_body_size integer;
+alter table requests add column response_body_size integer;
+alter table requests add column request_content_type text;
+alter table requests add column response_content_type text;
+alter table requests add column upstream_address text;
+
+update requests
+set
+    method = s.method,
+    url = s.url,
+    scheme = case
+        when instr(s.url, '://') > 0 then substr(s.url, 1, instr(s.url, '://') - 1)
+        else null
+    end,
+    host = s.host,
+    path = s.path,
+    query = case
+        when instr(s.url, '?') > 0 then substr(s.url, instr(s.url, '?') + 1)
+        else null
+    end,
+    status = s.status,
+    duration = s.duration,
+    request_body_size = json_extract(requests.har, '$.entries[0].request.bodySize'),
+    response_body_size = json_extract(requests.har, '$.entries[0].response.bodySize'),
+    request_content_type = coalesce(
+        json_extract(requests.har, '$.entries[0].request.postData.mimeType'),
+        (
+            select json_extract(h.value, '$.value')
+            from json_each(requests.har, '$.entries[0].request.headers') as h
+            where lower(json_extract(h.value, '$.name')) = 'content-type'
+        )
+    ),
+    response_content_type = json_extract(requests.har, '$.entries[0].response.content.mimeType'),
+    upstream_address = json_extract(requests.har, '$.entries[0].serverIPAddress')
+from request_summaries as s
+where s.request_id = requests.request_id;
+
+-- The columns make the view a plain projection. It is kept so queries read the same
+drop view request_summaries;
+create view request_summaries as
+select
+    request_id,
+    created_at,
+    har,
+    method,
+    url,
+    scheme,
+    host,
+    path,
+    query,
+    status,
+    duration,
+    request_body_size,
+    response_body_size,
+    request_content_type,
+    response_content_type,
+    upstream_address
+from requests;
+
+drop index request_method;
+drop index request_status;
+create index request_method on requests(method);
+create index request_status on requests(status);
+create index request_host on requests(lower(host));
+create index request_path on requests(path);
+
+-- Request and response headers of each request in the order they were sent
+create table request_headers (
+    request_id blob not null references requests(request_id) on delete cascade,
+    -- 'request' or 'response'
+    side text not null,
+    position integer not null,
+    name text not null,
+    value text not null
+);
+create index request_headers_request on request_headers(request_id);
+create index request_headers_name on request_headers(side, lower(name), value);
+
+insert into request_headers (request_id, side, position, name, value)
+select
+    r.request_id,
+    'request',
+    h.key,
+    coalesce(json_extract(h.value, '$.name'), ''),
+    coalesce(json_extract(h.value, '$.value'), '')
+from requests as r, json_each(r.har, '$.entries[0].request.headers') as h;
+
+insert into request_headers (request_id, side, position, name, value)
+select
+    r.request_id,
+    'response',
+    h.key,
+    coalesce(json_extract(h.value, '$.name'), ''),
+    coalesce(json_extract(h.value, '$.value'), '')
+from requests as r, json_each(r.har, '$.entries[0].response.headers') as h;
@@ -0,0 +1,35 @@
+-- Request and response bodies compressed with zstd, stored once however many requests share them
+create table bodies (
+    -- Hex SHA-256 of the body text as it appears in the HAR
+    hash text primary key,
+    data blob not null
+);
+
+-- The bodies of requests stored from now on are kept in bodies and replaced by null in the HAR
+alter table requests add column request_body text references bodies(hash);
+alter table requests add column response_body text references bodies(hash);
+create index request_request_body on requests(request_body);
+create index request_response_body on requests(response_body);
+
+drop view request_summaries;
+create view request_summaries as
+select
+    request_id,
+    created_at,
+    har,
+    method,
+    url,
+    scheme,
+    host,
+    path,
+    query,
+    status,
+    duration,
+    request_body_size,
+    response_body_size,
+    request_content_type,
+    response_content_type,
+    upstream_address,
+    request_body,
+    response_body
+from requests;
@@ -0,0 +1,6 @@
+-- When each request was stored, which max_age counts from. created_at is when the request was
+-- recorded, which can be long before it was imported. Existing requests count as stored now so
+-- none of them are deleted sooner than the retention settings promise
+alter table requests add column inserted_at integer;
+update requests set inserted_at = cast(strftime('%s', 'now') as integer);
+create index request_inserted_at on requests(inserted_at);
@@ -0,0 +1,13 @@
+-- Requests stored before the bodies table keep their bodies inline in the HAR until
+-- `park db migrate` moves them to bodies. Requests stored since never have inline bodies
+alter table requests add column inline_bodies integer not null default 0;
+update requests set inline_bodies = 1
+where (
+    json_type(har, '$.entries[0].request.postData.text') = 'text'
+    and json_extract(har, '$.entries[0].request.postData.text') != ''
+)
+or (
+    json_type(har, '$.entries[0].response.content.text') = 'text'
+    and json_extract(har, '$.entries[0].response.content.text') != ''
+);
+create index request_inline_bodies on requests(inline_bodies) where inline_bodies;
@@ -1,13 +1,20 @@
 use anyhow::Result;
-use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
+use futures_util::TryStreamExt;
+use har::v1_3::Headers;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
 use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use uuid::Uuid;
 
 use crate::config;
 use crate::db;
+use crate::export;
 use crate::har::Har;
+use crate::import;
 use crate::proxy::proxy;
+use crate::replay::{self, ReplayOptions};
 use crate::AppState;
 
 pub async fn api(
@@ -15,21 +22,161 @@ pub async fn api(
     state: AppState,
     req: Request<Incoming>,
 ) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    if let Some((request_id, action)) = path_request_id(req.uri().path()) {
+        return match (req.method(), action) {
+            (&Method::GET, None) => get_request(config, state, request_id).await,
+            (&Method::DELETE, None) => delete_request(config, state, request_id).await,
+            (&Method::POST, Some("replay")) => replay_request(config, state, req, request_id).await,
+            _ => Ok(not_found()),
+        };
+    }
+
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => {
             let body = Full::new(Bytes::from_static(b"Hello, World!")).map_err(anyhow::Error::from);
             Ok(Response::new(BoxBody::new(body)))
         }
+        (&Method::GET, "/requests") => list_requests(config, state, req).await,
         (&Method::GET, "/requests/latest") => latest_request(config, state, req).await,
+        (&Method::GET, "/requests/export") => export_requests(config, state, req).await,
+        (&Method::POST, "/requests/import") => import_requests(config, state, req).await,
+        (&Method::POST, "/requests/replay") => replay_requests(config, state, req).await,
         (&Method::POST, "/requests") => proxy_request(config, state, req).await,
-        _ => {
-            let body = Full::new(Bytes::from_static(b"Not found")).map_err(anyhow::Error::from);
-            Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(BoxBody::new(body))
-                .unwrap())
+        (&Method::GET, "/queue") => queue_stats(config, state, req).await,
+        _ => Ok(not_found()),
+    }
+}
+
+/// Split a `/requests/{id}` or `/requests/{id}/{action}` path into the id and action
+fn path_request_id(path: &str) -> Option<(Uuid, Option<&str>)> {
+    let rest = path.strip_prefix("/requests/")?;
+    let (id, action) = match rest.split_once('/') {
+        Some((id, action)) => (id, Some(action)),
+        None => (rest, None),
+    };
+
+    Uuid::parse_str(id).ok().map(|id| (id, action))
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Serialize)]
+struct RequestPage {
+    requests: Vec<db::RequestSummary>,
+    /// Cursor to pass as `before` to fetch the next page
+    next: Option<String>,
+}
+
+async fn list_requests(
+    _config: Arc<config::Config>,
+    state: AppState,
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let mut before = None;
+    let mut limit = DEFAULT_PAGE_SIZE;
+
+    let query = req.uri().query().unwrap_or_default();
+    let filter = match db::RequestFilter::from_query(query) {
+        Ok(filter) => filter,
+        Err(message) => return Ok(bad_request(message)),
+    };
+
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "before" => match Uuid::parse_str(&value) {
+                Ok(id) => before = Some(id),
+                Err(_) => return Ok(bad_request("Invalid before cursor")),
+            },
+            "limit" => match value.parse::<i64>() {
+                Ok(l) if (1..=MAX_PAGE_SIZE).contains(&l) => limit = l,
+                _ => return Ok(bad_request("Invalid limit")),
+            },
+            _ => {}
         }
     }
+
+    let requests = db::list_requests(&state.db, &filter, before, limit).await?;
+    let next = if requests.len() as i64 == limit {
+        requests.last().map(|r| r.request_id.clone())
+    } else {
+        None
+    };
+
+    let page = RequestPage { requests, next };
+    let body = Full::new(Bytes::from(serde_json::to_string(&page)?)).map_err(anyhow::Error::from);
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(BoxBody::new(body))
+        .unwrap())
+}
+
+/// Export the requests matching the filter as a single HAR document
+///
+/// Set `pages=true` to group entries into one page per host.
+async fn export_requests(
+    _config: Arc<config::Config>,
+    state: AppState,
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let query = req.uri().query().unwrap_or_default();
+    let filter = match db::RequestFilter::from_query(query) {
+        Ok(filter) => filter,
+        Err(message) => return Ok(bad_request(message)),
+    };
+    let pages = url::form_urlencoded::parse(query.as_bytes())
+        .any(|(key, value)| key == "pages" && value == "true");
+
+    let body = StreamBody::new(export::stream(state.db, filter, pages).map_ok(Frame::data));
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"park.har\"",
+        )
+        .body(BoxBody::new(body))
+        .unwrap())
+}
+
+#[derive(Serialize)]
+struct Imported {
+    request_ids: Vec<String>,
+}
+
+/// Store each entry of the uploaded HAR document as its own request
+async fn import_requests(
+    _config: Arc<config::Config>,
+    state: AppState,
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let body = req.collect().await?.to_bytes();
+    let har = match import::parse(&body) {
+        Ok(har) => har,
+        Err(err) => {
+            tracing::debug!("Failed to import HAR: {:?}", err);
+            return Ok(bad_request("Malformed har file"));
+        }
+    };
+    // Entries are stored in one transaction, so a failure leaves none of them stored
+    let request_ids = match import::store(&state.db, har).await {
+        Ok(request_ids) => request_ids.iter().map(Uuid::to_string).collect(),
+        Err(err) => {
+            tracing::error!("Failed to store imported HAR: {}", err);
+            return Ok(internal_error(
+                "Failed to store the imported requests. None of them were stored",
+            ));
+        }
+    };
+
+    let body = Full::new(Bytes::from(serde_json::to_string(&Imported {
+        request_ids,
+    })?))
+    .map_err(anyhow::Error::from);
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(BoxBody::new(body))
+        .unwrap())
 }
 
 async fn latest_request(
@@ -46,13 +193,44 @@ async fn latest_request(
                 Full::new(Bytes::from(serde_json::to_string(&har)?)).map_err(anyhow::Error::from);
             Ok(Response::new(BoxBody::new(body)))
         }
-        None => {
-            let body = Full::new(Bytes::from_static(b"Not found")).map_err(anyhow::Error::from);
+        None => Ok(not_found()),
+    }
+}
+
+async fn get_request(
+    _config: Arc<config::Config>,
+    state: AppState,
+    request_id: Uuid,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    match db::get_request(&state.db, request_id).await? {
+        Some(har) => {
+            let body =
+                Full::new(Bytes::from(serde_json::to_string(&har)?)).map_err(anyhow::Error::from);
             Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(
+                    http::header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}.har\"", request_id),
+                )
                 .body(BoxBody::new(body))
                 .unwrap())
         }
+        None => Ok(not_found()),
+    }
+}
+
+async fn delete_request(
+    _config: Arc<config::Config>,
+    state: AppState,
+    request_id: Uuid,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    if db::delete_request(&state.db, request_id).await? {
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(empty())
+            .unwrap())
+    } else {
+        Ok(not_found())
     }
 }
 
@@ -81,3 +259,147 @@ async fn proxy_request(
 
     Ok(res)
 }
+
+/// Changes applied to a stored request before it is replayed
+#[derive(Default, Deserialize)]
+struct ReplayOverrides {
+    /// Headers that replace every stored header with the same name
+    #[serde(default)]
+    headers: Vec<Headers>,
+
+    /// Replacement request body
+    body: Option<String>,
+
+    /// Set to `base64` when `body` is base64 encoded
+    encoding: Option<String>,
+}
+
+/// Send a stored request to the upstream server again
+///
+/// The response is returned as is. The id of the new recording is in the
+/// `x-park-request-id` response header.
+async fn replay_request(
+    config: Arc<config::Config>,
+    state: AppState,
+    req: Request<Incoming>,
+    request_id: Uuid,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let body = req.collect().await?.to_bytes();
+    let overrides: ReplayOverrides = if body.is_empty() {
+        ReplayOverrides::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(overrides) => overrides,
+            Err(err) => {
+                tracing::debug!("Failed to deserialize replay overrides: {:?}", err);
+                return Ok(bad_request("Malformed replay overrides"));
+            }
+        }
+    };
+
+    let mut har = match db::get_request(&state.db, request_id).await? {
+        Some(har) => har,
+        None => return Ok(not_found()),
+    };
+
+    if let Some(request) = har.request_mut() {
+        for header in overrides.headers {
+            request
+                .headers
+                .retain(|h| !h.name.eq_ignore_ascii_case(&header.name));
+            request.headers.push(header);
+        }
+
+        if let Some(body) = overrides.body {
+            // The stored length describes the original body
+            request
+                .headers
+                .retain(|h| !h.name.eq_ignore_ascii_case("content-length"));
+
+            let post_data = request.post_data.get_or_insert_with(Default::default);
+            post_data.text = Some(body);
+            post_data.encoding = overrides.encoding;
+        }
+    }
+
+    let req = hyper::Request::try_from(har)?;
+
+    let res = proxy(config, state, req).await?;
+
+    Ok(res)
+}
+
+/// Replay a batch of stored requests and report which responses changed
+async fn replay_requests(
+    config: Arc<config::Config>,
+    state: AppState,
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let body = req.collect().await?.to_bytes();
+    let options: ReplayOptions = if body.is_empty() {
+        ReplayOptions::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(options) => options,
+            Err(err) => {
+                tracing::debug!("Failed to deserialize replay options: {:?}", err);
+                return Ok(bad_request("Malformed replay options"));
+            }
+        }
+    };
+
+    if let Err(message) = db::RequestFilter::from_query(&options.filter) {
+        return Ok(bad_request(message));
+    }
+
+    let report = replay::run(config, state, &options).await?;
+
+    let body = Full::new(Bytes::from(serde_json::to_string(&report)?)).map_err(anyhow::Error::from);
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(BoxBody::new(body))
+        .unwrap())
+}
+
+/// Report how many recordings were dropped, retried or spilled by the HAR queue
+async fn queue_stats(
+    _config: Arc<config::Config>,
+    state: AppState,
+    _req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error> {
+    let stats = state.har_queue.stats();
+
+    let body = Full::new(Bytes::from(serde_json::to_string(&stats)?)).map_err(anyhow::Error::from);
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(BoxBody::new(body))
+        .unwrap())
+}
+
+fn empty() -> BoxBody<Bytes, anyhow::Error> {
+    BoxBody::new(Full::new(Bytes::new()).map_err(anyhow::Error::from))
+}
+
+fn not_found() -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let body = Full::new(Bytes::from_static(b"Not found")).map_err(anyhow::Error::from);
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(BoxBody::new(body))
+        .unwrap()
+}
+
+fn internal_error(message: &'static str) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let body = Full::new(Bytes::from_static(message.as_bytes())).map_err(anyhow::Error::from);
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(BoxBody::new(body))
+        .unwrap()
+}
+
+fn bad_request(message: &'static str) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let body = Full::new(Bytes::from_static(message.as_bytes())).map_err(anyhow::Error::from);
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(BoxBody::new(body))
+        .unwrap()
+}
@@ -1,5 +1,8 @@
+use std::borrow::Cow;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
+use regex::Regex;
 use serde::Deserialize;
 use url::Url;
 
@@ -7,6 +10,29 @@ use url::Url;
 pub struct Config {
     pub database: Database,
     pub server: Server,
+
+    #[serde(default)]
+    pub mock: Mock,
+
+    #[serde(default)]
+    pub intercept: Intercept,
+
+    #[serde(default)]
+    pub queue: Queue,
+
+    /// Upstream servers selected by the request path and host
+    ///
+    /// Routes are checked in order and the first match is used. Requests that do not match any
+    /// route are sent to `server.address`.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+impl Config {
+    /// The first route matching the request host and path
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(host, path))
+    }
 }
 
 #[derive(Deserialize)]
@@ -19,15 +45,37 @@ pub struct Database {
 
     /// Maximum size of the database in bytes
     ///
-    /// Defaults to 10MiB
+    /// Pages freed by deleted requests are returned to the filesystem once the database file is
+    /// larger than this. Defaults to 10MiB
     #[serde(default = "default_max_size")]
     pub max_size: u64,
+
+    /// Delete requests stored more than this many seconds ago. Imported requests count from when
+    /// they were imported rather than when they were recorded. Defaults to keeping every request
+    pub max_age: Option<u64>,
+
+    /// Delete the oldest requests once more than this many are stored. Defaults to no limit
+    pub max_rows: Option<u64>,
+
+    /// Delete the oldest requests once the stored HARs and compressed bodies take up more than
+    /// this many bytes. The database file is larger by its indexes and free pages. Defaults to
+    /// no limit
+    pub max_bytes: Option<u64>,
+
+    /// How often in seconds to delete requests past the limits and reclaim free pages. Defaults
+    /// to 60 seconds
+    #[serde(default = "default_prune_interval")]
+    pub prune_interval: u64,
 }
 
 const fn default_max_size() -> u64 {
     10 * 1024 * 1024
 }
 
+const fn default_prune_interval() -> u64 {
+    60
+}
+
 #[derive(Deserialize)]
 pub struct Server {
     /// The address of the upstream/backend server to proxy requests to
@@ -35,8 +83,11 @@ pub struct Server {
     /// Options:
     /// - IP address and port
     /// - URL
-    #[serde(deserialize_with = "deserialize_address")]
-    pub address: Url,
+    ///
+    /// When not set park is a forward proxy and sends each request to the host it names, as long
+    /// as `destinations` allows it.
+    #[serde(default, deserialize_with = "deserialize_optional_address")]
+    pub address: Option<Url>,
 
     /// listen for requests on a given IP address and port. Defaults to 127.0.0.1:3000
     #[serde(default = "default_bind")]
@@ -54,6 +105,11 @@ pub struct Server {
     #[serde(default = "default_server_timeout")]
     pub server_timeout: u64,
 
+    /// The time in seconds to wait for open connections, and then for their HARs to be queued,
+    /// when shutting down. Defaults to 30 seconds
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+
     /// The path to the SSL certificate pem file
     ///
     /// Required if the listener uses TLS
@@ -63,6 +119,366 @@ pub struct Server {
     ///
     /// Required if the listener uses TLS
     pub ssl_key: Option<String>,
+
+    /// Headers added to proxied requests to describe the downstream client
+    #[serde(default)]
+    pub forwarding: Forwarding,
+
+    /// Hosts park may send requests to when it is used as a forward proxy or opens a CONNECT
+    /// tunnel
+    #[serde(default)]
+    pub destinations: Destinations,
+
+    #[serde(default)]
+    pub tunnel: Tunnel,
+
+    #[serde(default)]
+    pub websocket: WebSocket,
+}
+
+impl Server {
+    /// Whether requests may be sent to `host`
+    pub fn allows_destination(&self, host: &str) -> bool {
+        let matches = |pattern: &String| host_matches(pattern, host);
+        let destinations = &self.destinations;
+
+        let allowed = match &destinations.allowed_hosts {
+            Some(allowed_hosts) => allowed_hosts.iter().any(matches),
+            None => self.address.is_some(),
+        };
+
+        allowed && !destinations.denied_hosts.iter().any(matches)
+    }
+
+    /// Whether a CONNECT tunnel may be opened to `host` and `port`
+    pub fn allows_tunnel(&self, host: &str, port: u16) -> bool {
+        let allowed_port = match &self.destinations.allowed_ports {
+            Some(allowed_ports) => allowed_ports.contains(&port),
+            None => self.address.is_some() || port == 443,
+        };
+
+        allowed_port && self.allows_destination(host)
+    }
+}
+
+#[derive(Default, Deserialize)]
+pub struct Destinations {
+    /// Hosts requests may be sent to. `*.example.com` matches every subdomain of example.com and
+    /// `*` matches any host
+    ///
+    /// Defaults to any host when `server.address` is set, which only affects CONNECT tunnels, and
+    /// to none in forward proxy mode so park is not an open relay.
+    pub allowed_hosts: Option<Vec<String>>,
+
+    /// Hosts requests are never sent to even if they are allowed. Uses the same patterns as
+    /// `allowed_hosts`
+    #[serde(default)]
+    pub denied_hosts: Vec<String>,
+
+    /// Ports CONNECT tunnels may be opened to. Defaults to any port when `server.address` is set
+    /// and to 443 in forward proxy mode
+    pub allowed_ports: Option<Vec<u16>>,
+}
+
+#[derive(Deserialize)]
+pub struct Tunnel {
+    /// The timeout in seconds for connecting to the target of a CONNECT request. Defaults to 10
+    /// seconds
+    #[serde(default = "default_tunnel_connect_timeout")]
+    pub connect_timeout: u64,
+
+    /// Close CONNECT tunnels after this many seconds without data in either direction. Defaults
+    /// to 300 seconds
+    #[serde(default = "default_tunnel_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Default for Tunnel {
+    fn default() -> Self {
+        Tunnel {
+            connect_timeout: default_tunnel_connect_timeout(),
+            idle_timeout: default_tunnel_idle_timeout(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WebSocket {
+    /// The most frames recorded for a WebSocket connection. Later frames are still relayed but
+    /// only counted. Defaults to 1000
+    #[serde(default = "default_websocket_max_messages")]
+    pub max_messages: usize,
+
+    /// The most payload bytes recorded for a WebSocket connection. Frames past the limit are
+    /// still relayed but only counted. Defaults to 1MiB
+    #[serde(default = "default_websocket_max_bytes")]
+    pub max_bytes: usize,
+}
+
+impl Default for WebSocket {
+    fn default() -> Self {
+        WebSocket {
+            max_messages: default_websocket_max_messages(),
+            max_bytes: default_websocket_max_bytes(),
+        }
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .len()
+            .checked_sub(domain.len() + 1)
+            .and_then(|start| host.get(start..))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .is_some_and(|suffix| suffix.eq_ignore_ascii_case(domain)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Forwarding {
+    /// Add a `Via` header to proxied requests and responses. Defaults to true
+    #[serde(default = "default_true")]
+    pub via: bool,
+
+    /// The name park uses for itself in the `Via` header. Defaults to park
+    #[serde(default = "default_pseudonym")]
+    pub pseudonym: String,
+
+    /// Add `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers to proxied
+    /// requests. Defaults to true
+    #[serde(default = "default_true")]
+    pub x_forwarded: bool,
+
+    /// Add a `Forwarded` header to proxied requests. Defaults to false
+    #[serde(default)]
+    pub forwarded: bool,
+
+    /// Send the `Host` header of the downstream request instead of the host of the upstream
+    /// server. Defaults to false
+    #[serde(default)]
+    pub preserve_host: bool,
+}
+
+impl Default for Forwarding {
+    fn default() -> Self {
+        Forwarding {
+            via: true,
+            pseudonym: default_pseudonym(),
+            x_forwarded: true,
+            forwarded: false,
+            preserve_host: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Route {
+    /// Recorded in the comment of HAR entries sent through this route. Defaults to the address
+    pub name: Option<String>,
+
+    /// Match requests whose path starts with this prefix. Only whole path segments match, so
+    /// `/api` matches `/api` and `/api/users` but not `/apiv2`
+    pub path_prefix: Option<String>,
+
+    /// Match requests whose path matches this regular expression
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub path_regex: Option<Regex>,
+
+    /// Only match requests for this host name. Any port is ignored
+    pub host: Option<String>,
+
+    /// The address of the upstream/backend server to proxy matching requests to
+    #[serde(deserialize_with = "deserialize_address")]
+    pub address: Url,
+
+    /// Remove `path_prefix` from the path before proxying. Defaults to false
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+impl Route {
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| self.address.to_string())
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        if let Some(expected) = &self.host {
+            match host {
+                Some(host) if host.eq_ignore_ascii_case(expected) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(prefix) = &self.path_prefix {
+            if strip_path_prefix(path, prefix).is_none() {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &self.path_regex {
+            if !regex.is_match(path) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// The path to send upstream
+    pub fn upstream_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match (&self.path_prefix, self.strip_prefix) {
+            (Some(prefix), true) => {
+                let stripped = strip_path_prefix(path, prefix).unwrap_or(path);
+                if stripped.starts_with('/') {
+                    Cow::Borrowed(stripped)
+                } else {
+                    Cow::Owned(format!("/{}", stripped))
+                }
+            }
+            _ => Cow::Borrowed(path),
+        }
+    }
+}
+
+/// The rest of `path` after `prefix` if the prefix ends on a path segment boundary
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix.trim_end_matches('/'))?;
+
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+#[derive(Deserialize)]
+pub struct Mock {
+    /// Answer requests with recorded responses instead of forwarding them to the upstream server.
+    /// Defaults to false
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The path to a HAR file to read recorded responses from
+    ///
+    /// Recorded responses are read from the database if not set
+    pub har_file: Option<String>,
+
+    /// Require the HTTP method to match. Defaults to true
+    #[serde(default = "default_true")]
+    pub match_method: bool,
+
+    /// Require the path to match. Defaults to true
+    #[serde(default = "default_true")]
+    pub match_path: bool,
+
+    /// Require the query parameters to match in any order. Defaults to true
+    #[serde(default = "default_true")]
+    pub match_query: bool,
+
+    /// Require the request body to match. Defaults to false
+    #[serde(default)]
+    pub match_body: bool,
+
+    /// Proxy requests without a recorded response to the upstream server and record them.
+    /// Defaults to false
+    #[serde(default)]
+    pub fallthrough: bool,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Mock {
+            enabled: false,
+            har_file: None,
+            match_method: true,
+            match_path: true,
+            match_query: true,
+            match_body: false,
+            fallthrough: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Intercept {
+    /// Decrypt and record the HTTPS traffic sent through CONNECT tunnels instead of copying the
+    /// encrypted bytes. Clients must trust the root certificate. Defaults to false
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The path to the root certificate pem file
+    ///
+    /// A new root certificate and key are generated if either file does not exist. Defaults to
+    /// park-ca.pem
+    #[serde(default = "default_ca_cert")]
+    pub ca_cert: PathBuf,
+
+    /// The path to the root certificate private key pem file. Defaults to park-ca-key.pem
+    #[serde(default = "default_ca_key")]
+    pub ca_key: PathBuf,
+}
+
+impl Default for Intercept {
+    fn default() -> Self {
+        Intercept {
+            enabled: false,
+            ca_cert: default_ca_cert(),
+            ca_key: default_ca_key(),
+        }
+    }
+}
+
+/// How HARs waiting to be written to the database are buffered
+#[derive(Deserialize)]
+pub struct Queue {
+    /// The number of HARs held in memory. Defaults to 1000
+    #[serde(default = "default_queue_capacity")]
+    pub capacity: usize,
+
+    /// What to do with a HAR when the queue is full. Defaults to `block`
+    #[serde(default)]
+    pub overflow: Overflow,
+
+    /// The file HARs are appended to when `overflow` is `spill`. Its contents are written to the
+    /// database once the queue is empty, from a copy with `.replay` appended to its name
+    #[serde(default = "default_spill_file")]
+    pub spill_file: PathBuf,
+
+    /// How many times to retry writing a batch of HARs the database rejected. Defaults to 5
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            capacity: default_queue_capacity(),
+            overflow: Overflow::default(),
+            spill_file: default_spill_file(),
+            max_retries: default_max_retries(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Wait for room in the queue before finishing the recording
+    #[default]
+    Block,
+
+    /// Drop the oldest queued HAR to make room
+    DropOldest,
+
+    /// Drop the HAR being queued
+    DropNewest,
+
+    /// Append the HAR to `spill_file`. Batches that cannot be written are spilled too
+    Spill,
 }
 
 fn deserialize_address<'de, D>(deserializer: D) -> Result<Url, D::Error>
@@ -81,6 +497,25 @@ where
     }
 }
 
+fn deserialize_optional_address<'de, D>(deserializer: D) -> Result<Option<Url>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_address(deserializer).map(Some)
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(pattern) => Regex::new(&pattern)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
 fn default_bind() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 3000))
 }
@@ -96,3 +531,51 @@ const fn default_client_timeout() -> usize {
 const fn default_server_timeout() -> u64 {
     10
 }
+
+const fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+const fn default_tunnel_connect_timeout() -> u64 {
+    10
+}
+
+const fn default_tunnel_idle_timeout() -> u64 {
+    300
+}
+
+const fn default_websocket_max_messages() -> usize {
+    1000
+}
+
+const fn default_websocket_max_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_pseudonym() -> String {
+    "park".to_string()
+}
+
+const fn default_queue_capacity() -> usize {
+    1000
+}
+
+fn default_spill_file() -> PathBuf {
+    PathBuf::from("park-spill.jsonl")
+}
+
+const fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_ca_cert() -> PathBuf {
+    PathBuf::from("park-ca.pem")
+}
+
+fn default_ca_key() -> PathBuf {
+    PathBuf::from("park-ca-key.pem")
+}
+
+const fn default_true() -> bool {
+    true
+}
@@ -1,56 +1,392 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
 use anyhow::Result;
-use sqlx::sqlite::SqlitePool;
+use har::v1_3::Headers;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{
+    Sqlite, SqliteAutoVacuum, SqliteConnectOptions, SqliteConnection, SqlitePool, SqliteRow,
+};
 use sqlx::QueryBuilder;
 use sqlx::Row;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
 use crate::config;
-use crate::har::Har;
+use crate::har::{Har, Recording};
 
 pub async fn init_db(db_config: &config::Database) -> Result<SqlitePool> {
     tracing::trace!("init_db");
-    let pool = SqlitePool::connect(&db_config.uri).await?;
+    let pool = connect(&db_config.uri).await?;
 
-    let user_tables: (i64,) = sqlx::query_as(
-        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%'",
-    )
-    .fetch_one(&pool)
-    .await?;
+    // The migrator skips versions it has already applied, so running it on an existing database
+    // only applies the migrations added since
+    tracing::info!("Running migrations...");
+    crate::migrate::run(&pool).await.inspect_err(|_| {
+        tracing::error!("Failed to run migrations");
+    })?;
 
-    if user_tables.0 == 0 {
-        tracing::info!("Running migrations...");
-        sqlx::migrate!().run(&pool).await.map_err(|err| {
-            tracing::error!("Failed to run migrations");
-            err
-        })?;
-    } else {
-        tracing::info!("Existing tables found, skipping migrations");
+    // Converting a database to incremental vacuum rewrites the whole file, which can take minutes
+    // for a large one, so it is left to `park db migrate`
+    if let Some(size) = without_incremental_vacuum(&pool).await? {
+        tracing::warn!(
+            "The database does not return the space freed by deleted requests to the filesystem. Run `park db migrate` to convert it, which rewrites the whole {} MiB file once",
+            size / (1024 * 1024)
+        );
     }
 
-    let max_size = db_config.max_size;
+    let inline = inline_body_count(&pool).await?;
+    if inline > 0 {
+        tracing::warn!(
+            "{} requests stored by an earlier version of park keep their bodies uncompressed. Run `park db migrate` to compress them",
+            inline
+        );
+    }
+
+    let retention = Retention {
+        max_size: db_config.max_size,
+        max_age: db_config.max_age,
+        max_rows: db_config.max_rows,
+        max_bytes: db_config.max_bytes,
+    };
+    let interval = std::time::Duration::from_secs(db_config.prune_interval);
     let pool2 = pool.clone();
     tokio::spawn(async move {
         loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
-            let size = sqlx::query_scalar(
-                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
-            )
-            .fetch_one(&pool2)
-            .await
-            .unwrap_or(0u64);
+            tokio::time::sleep(interval).await;
 
-            if size > max_size {
-                sqlx::query("VACUUM").execute(&pool2).await.unwrap();
+            match prune(&pool2, &retention).await {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    tracing::debug!("Deleted {} requests past the retention limits", deleted)
+                }
+                Err(err) => tracing::error!("Failed to delete old requests: {}", err),
             }
+            let _ = reclaim_space(&pool2, retention.max_size)
+                .await
+                .inspect_err(|err| tracing::error!("Failed to vacuum the database: {}", err));
         }
     });
 
     Ok(pool)
 }
 
-pub async fn insert_request(pool: &SqlitePool, har: &mut Vec<Har>) -> Result<()> {
-    tracing::trace!("insert_request");
+/// Open a pool on the database at `uri`
+///
+/// Databases created through the pool use incremental vacuum from the start. Existing databases
+/// keep their setting until they are converted with [`enable_incremental_vacuum`].
+pub async fn connect(uri: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(uri)?.auto_vacuum(SqliteAutoVacuum::Incremental);
+
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+/// The size in bytes of the database when it does not use incremental vacuum
+pub async fn without_incremental_vacuum(pool: &SqlitePool) -> Result<Option<i64>> {
+    let (auto_vacuum, size): (i64, i64) = sqlx::query_as(
+        r#"
+        SELECT auto_vacuum, page_count * page_size
+        FROM pragma_auto_vacuum(), pragma_page_count(), pragma_page_size()
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok((auto_vacuum != INCREMENTAL_VACUUM).then_some(size))
+}
+
+/// Convert the database to incremental vacuum so pages freed by deleted requests can be
+/// reclaimed without rewriting the whole file
+///
+/// The conversion itself is a full `VACUUM`, which rewrites the whole file.
+pub async fn enable_incremental_vacuum(pool: &SqlitePool) -> Result<()> {
     let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("VACUUM").execute(&mut *conn).await?;
+
+    Ok(())
+}
+
+/// The value of `PRAGMA auto_vacuum` when incremental vacuum is enabled
+const INCREMENTAL_VACUUM: i64 = 2;
+
+/// The most requests deleted in one statement, so writers are not blocked for long
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+/// The most free pages returned to the filesystem in one statement
+const VACUUM_BATCH_PAGES: i64 = 1000;
+
+/// Limits past which the oldest requests are deleted
+struct Retention {
+    max_size: u64,
+    max_age: Option<u64>,
+    max_rows: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+/// Delete the oldest requests until every retention limit is met
+///
+/// Returns the number of requests deleted.
+async fn prune(pool: &SqlitePool, retention: &Retention) -> Result<u64> {
+    tracing::trace!("prune");
+    let mut deleted = 0;
+
+    if let Some(max_age) = retention.max_age {
+        let cutoff = chrono::Utc::now().timestamp() - max_age as i64;
+        loop {
+            let count = sqlx::query(
+                r#"
+                DELETE FROM requests
+                WHERE request_id IN (
+                    SELECT request_id FROM requests
+                    WHERE inserted_at < ?
+                    ORDER BY inserted_at, request_id
+                    LIMIT ?
+                )"#,
+            )
+            .bind(cutoff)
+            .bind(PRUNE_BATCH_SIZE)
+            .execute(pool)
+            .await?
+            .rows_affected();
+            deleted += count;
+
+            if count < PRUNE_BATCH_SIZE as u64 {
+                break;
+            }
+        }
+    }
+
+    if let Some(max_rows) = retention.max_rows {
+        loop {
+            let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM requests")
+                .fetch_one(pool)
+                .await?;
+            let excess = rows - max_rows as i64;
+            if excess <= 0 {
+                break;
+            }
+
+            deleted += delete_oldest(pool, excess.min(PRUNE_BATCH_SIZE)).await?;
+        }
+    }
+
+    if let Some(max_bytes) = retention.max_bytes {
+        let mut used = stored_bytes(pool).await?;
+        while used > max_bytes as i64 {
+            let excess = used - max_bytes as i64;
+
+            // Delete just enough of the oldest requests to free the excess, counting only the
+            // bodies no other request shares since the rest stay stored
+            let candidates: Vec<(String, i64)> = sqlx::query_as(&format!(
+                r#"
+                SELECT
+                    request_id,
+                    octet_length(har)
+                    + coalesce((
+                        SELECT length(data) FROM bodies
+                        WHERE hash = r.request_body AND {unshared}
+                    ), 0)
+                    + coalesce((
+                        SELECT length(data) FROM bodies
+                        WHERE hash = r.response_body AND r.response_body IS NOT r.request_body
+                        AND {unshared}
+                    ), 0)
+                FROM requests AS r
+                ORDER BY created_at, request_id
+                LIMIT ?
+                "#,
+                unshared = UNSHARED_BODY
+            ))
+            .bind(PRUNE_BATCH_SIZE)
+            .fetch_all(pool)
+            .await?;
+
+            let mut freed = 0;
+            let request_ids: Vec<&str> = candidates
+                .iter()
+                .take_while(|(_, bytes)| {
+                    let more = freed < excess;
+                    freed += bytes;
+                    more
+                })
+                .map(|(request_id, _)| request_id.as_str())
+                .collect();
+            if request_ids.is_empty() {
+                break;
+            }
+            deleted += delete_requests(pool, &request_ids).await?;
+
+            // Bodies only stop taking up space once no request refers to them
+            delete_orphaned_bodies(pool).await?;
+
+            let previous = used;
+            used = stored_bytes(pool).await?;
+            if used >= previous {
+                break;
+            }
+        }
+    }
+
+    // Also covers bodies of requests deleted through the API
+    delete_orphaned_bodies(pool).await?;
+
+    Ok(deleted)
+}
+
+/// Delete the bodies no stored request refers to
+async fn delete_orphaned_bodies(pool: &SqlitePool) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM bodies
+        WHERE NOT EXISTS (SELECT 1 FROM requests WHERE request_body = bodies.hash)
+        AND NOT EXISTS (SELECT 1 FROM requests WHERE response_body = bodies.hash)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Matches a body in `bodies` that no request other than `r` refers to
+const UNSHARED_BODY: &str = r#"
+    NOT EXISTS (
+        SELECT 1 FROM requests WHERE request_body = bodies.hash AND request_id != r.request_id
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM requests WHERE response_body = bodies.hash AND request_id != r.request_id
+    )"#;
+
+/// The bytes taken up by stored HARs and compressed bodies, which `max_bytes` limits
+async fn stored_bytes(pool: &SqlitePool) -> Result<i64> {
+    let bytes = sqlx::query_scalar(
+        r#"
+        SELECT
+            (SELECT coalesce(sum(octet_length(har)), 0) FROM requests)
+            + (SELECT coalesce(sum(length(data)), 0) FROM bodies)
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(bytes)
+}
+
+/// Delete the requests with the given ids
+async fn delete_requests(pool: &SqlitePool, request_ids: &[&str]) -> Result<u64> {
+    let mut query = QueryBuilder::new("DELETE FROM requests WHERE request_id IN (");
+    let mut ids = query.separated(", ");
+    for request_id in request_ids {
+        ids.push_bind(*request_id);
+    }
+    query.push(")");
+
+    Ok(query.build().execute(pool).await?.rows_affected())
+}
+
+/// Delete the `count` oldest requests
+async fn delete_oldest(pool: &SqlitePool, count: i64) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM requests
+        WHERE request_id IN (
+            SELECT request_id FROM requests
+            ORDER BY created_at, request_id
+            LIMIT ?
+        )"#,
+    )
+    .bind(count)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Return free pages to the filesystem when the database file is larger than `max_size`
+///
+/// Pages are released in small batches with incremental vacuum so the database is never locked
+/// for as long as a full `VACUUM` would.
+async fn reclaim_space(pool: &SqlitePool, max_size: u64) -> Result<()> {
+    let size: i64 = sqlx::query_scalar(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+    )
+    .fetch_one(pool)
+    .await?;
+    if size as u64 <= max_size {
+        return Ok(());
+    }
+
+    let mut previous = i64::MAX;
+    loop {
+        let free_pages: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+            .fetch_one(pool)
+            .await?;
+        // Nothing is released when incremental vacuum is disabled, such as for in-memory
+        // databases
+        if free_pages == 0 || free_pages >= previous {
+            return Ok(());
+        }
+        previous = free_pages;
+
+        sqlx::query(&format!(
+            "PRAGMA incremental_vacuum({})",
+            VACUUM_BATCH_PAGES
+        ))
+        .execute(pool)
+        .await?;
+    }
+}
+
+/// The most headers inserted in one statement, keeping well below SQLite's limit on bound
+/// parameters
+const HEADER_BATCH_SIZE: usize = 1000;
+
+/// Store recordings along with the columns, headers and bodies extracted from their HAR
+///
+/// Bodies are stored compressed in the `bodies` table, once per distinct body, and replaced by
+/// null in the stored HAR. Recordings whose id is already stored are skipped so a batch can be
+/// retried safely.
+pub async fn insert_request(pool: &SqlitePool, recordings: &[Recording]) -> Result<()> {
+    tracing::trace!("insert_request");
+
+    let mut tx = pool.begin().await?;
+    insert_requests_with(&mut tx, recordings).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Store recordings like [`insert_request`] on `conn`, so several batches can share a
+/// transaction
+pub async fn insert_requests_with(
+    conn: &mut SqliteConnection,
+    recordings: &[Recording],
+) -> Result<()> {
+    let rows: Vec<_> = recordings
+        .iter()
+        .filter_map(|recording| match StoredHar::new(recording) {
+            Ok(row) => Some(row),
+            Err(_) => {
+                tracing::error!("Failed to serialize HAR to JSON");
+                None
+            }
+        })
+        .collect();
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let bodies = rows
+        .iter()
+        .flat_map(|row| [&row.request_body, &row.response_body])
+        .flatten()
+        .collect();
+    insert_bodies(conn, bodies).await?;
 
     let mut query = QueryBuilder::new(
         r#"
@@ -58,64 +394,580 @@ pub async fn insert_request(pool: &SqlitePool, har: &mut Vec<Har>) -> Result<()>
         (
             request_id,
             har,
-            created_at
+            created_at,
+            inserted_at,
+            method,
+            url,
+            scheme,
+            host,
+            path,
+            query,
+            status,
+            duration,
+            request_body_size,
+            response_body_size,
+            request_content_type,
+            response_content_type,
+            upstream_address,
+            request_body,
+            response_body
         )"#,
     );
+    let inserted_at = chrono::Utc::now().timestamp();
+    query.push_values(&rows, |mut b, row| {
+        let columns = &row.columns;
+        b.push_bind(row.recording.request_id.to_string())
+            .push_bind(row.har_json.as_str())
+            .push_bind(row.recording.created_at)
+            .push_bind(inserted_at)
+            .push_bind(columns.method)
+            .push_bind(columns.url)
+            .push_bind(columns.scheme)
+            .push_bind(columns.host)
+            .push_bind(columns.path)
+            .push_bind(columns.query)
+            .push_bind(columns.status)
+            .push_bind(columns.duration)
+            .push_bind(columns.request_body_size)
+            .push_bind(columns.response_body_size)
+            .push_bind(columns.request_content_type)
+            .push_bind(columns.response_content_type)
+            .push_bind(columns.upstream_address)
+            .push_bind(row.request_body.as_ref().map(|body| body.hash.as_str()))
+            .push_bind(row.response_body.as_ref().map(|body| body.hash.as_str()));
+    });
+    query.push(" ON CONFLICT (request_id) DO NOTHING RETURNING request_id");
 
-    let iter = har
-        .drain(..)
-        .map(|har| {
-            let request_id = Uuid::now_v7();
-            match serde_json::to_string(&har) {
-                Ok(har_json) => Ok((request_id, har_json)),
-                Err(err) => {
-                    tracing::error!("Failed to serialize HAR to JSON");
-                    Err(err)
-                }
+    let inserted: HashSet<String> = query
+        .build_query_scalar()
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    let headers: Vec<_> = rows
+        .iter()
+        .map(|row| (row.recording.request_id.to_string(), &row.recording.har))
+        .filter(|(request_id, _)| inserted.contains(request_id))
+        .flat_map(|(request_id, har)| {
+            let request = har
+                .request()
+                .map(|r| r.headers.as_slice())
+                .unwrap_or_default();
+            let response = har
+                .response()
+                .map(|r| r.headers.as_slice())
+                .unwrap_or_default();
+            let sides = [("request", request), ("response", response)];
+
+            sides.into_iter().flat_map(move |(side, headers)| {
+                let request_id = request_id.clone();
+                headers.iter().enumerate().map(move |(position, header)| {
+                    (request_id.clone(), side, position as i64, header)
+                })
+            })
+        })
+        .collect();
+
+    for chunk in headers.chunks(HEADER_BATCH_SIZE) {
+        let mut query = QueryBuilder::new(
+            "INSERT INTO request_headers (request_id, side, position, name, value)",
+        );
+        query.push_values(chunk, |mut b, (request_id, side, position, header)| {
+            b.push_bind(request_id.as_str())
+                .push_bind(*side)
+                .push_bind(*position)
+                .push_bind(header.name.as_str())
+                .push_bind(header.value.as_str());
+        });
+        query.build().execute(&mut *conn).await?;
+    }
+
+    Ok(())
+}
+
+/// Where the bodies are in the JSON of a HAR
+const REQUEST_BODY_POINTER: &str = "/entries/0/request/postData/text";
+const RESPONSE_BODY_POINTER: &str = "/entries/0/response/content/text";
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// A recording as it is stored: the JSON of its HAR without the bodies, the bodies and the
+/// columns
+struct StoredHar<'a> {
+    recording: &'a Recording,
+    har_json: String,
+    request_body: Option<Body>,
+    response_body: Option<Body>,
+    columns: Columns<'a>,
+}
+
+impl<'a> StoredHar<'a> {
+    fn new(recording: &'a Recording) -> serde_json::Result<Self> {
+        let mut har = serde_json::to_value(&recording.har)?;
+        let (request_body, response_body) = take_bodies(&mut har);
+
+        Ok(StoredHar {
+            recording,
+            har_json: serde_json::to_string(&har)?,
+            request_body,
+            response_body,
+            columns: Columns::from_har(&recording.har),
+        })
+    }
+}
+
+/// Take the request and response bodies out of the JSON of a HAR, leaving null in their place
+fn take_bodies(har: &mut Value) -> (Option<Body>, Option<Body>) {
+    let mut take_body = |pointer| match har.pointer_mut(pointer).map(Value::take) {
+        Some(Value::String(text)) if !text.is_empty() => Some(Body::new(text)),
+        Some(value) => {
+            // Put back anything that is not a body worth storing on its own
+            if let Some(slot) = har.pointer_mut(pointer) {
+                *slot = value;
             }
+            None
+        }
+        None => None,
+    };
+
+    (
+        take_body(REQUEST_BODY_POINTER),
+        take_body(RESPONSE_BODY_POINTER),
+    )
+}
+
+/// How many requests stored by earlier versions of park still hold their bodies in the HAR
+pub async fn inline_body_count(pool: &SqlitePool) -> Result<i64> {
+    let count = sqlx::query_scalar("SELECT COUNT(*) FROM requests WHERE inline_bodies")
+        .fetch_one(pool)
+        .await?;
+
+    Ok(count)
+}
+
+/// The most requests whose inline bodies are compressed in one transaction
+const COMPRESS_BATCH_SIZE: i64 = 100;
+
+/// Move the bodies of requests stored by earlier versions of park out of their HAR and into the
+/// `bodies` table, the same way new requests are stored
+///
+/// Returns the number of requests whose bodies were moved.
+pub async fn compress_inline_bodies(pool: &SqlitePool) -> Result<u64> {
+    tracing::trace!("compress_inline_bodies");
+    let mut compressed = 0;
+
+    loop {
+        let rows: Vec<(String, Vec<u8>)> = sqlx::query_as(
+            "SELECT request_id, json(har) FROM requests WHERE inline_bodies LIMIT ?",
+        )
+        .bind(COMPRESS_BATCH_SIZE)
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let stored = rows
+            .into_iter()
+            .map(|(request_id, har_json)| {
+                let mut har: Value = serde_json::from_slice(&har_json)?;
+                let (request_body, response_body) = take_bodies(&mut har);
+                Ok((
+                    request_id,
+                    serde_json::to_string(&har)?,
+                    request_body,
+                    response_body,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut tx = pool.begin().await?;
+        let bodies = stored
+            .iter()
+            .flat_map(|(_, _, request_body, response_body)| [request_body, response_body])
+            .flatten()
+            .collect();
+        insert_bodies(&mut tx, bodies).await?;
+        for (request_id, har_json, request_body, response_body) in &stored {
+            sqlx::query(
+                r#"
+                UPDATE requests
+                SET har = ?, request_body = ?, response_body = ?, inline_bodies = 0
+                WHERE request_id = ?
+                "#,
+            )
+            .bind(har_json)
+            .bind(request_body.as_ref().map(|body| body.hash.as_str()))
+            .bind(response_body.as_ref().map(|body| body.hash.as_str()))
+            .bind(request_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        compressed += stored.len() as u64;
+    }
+
+    Ok(compressed)
+}
+
+/// The text of a request or response body as it appears in the HAR
+struct Body {
+    /// Hex SHA-256 of the text
+    hash: String,
+    text: String,
+}
+
+impl Body {
+    fn new(text: String) -> Self {
+        Body {
+            hash: format!("{:x}", Sha256::digest(text.as_bytes())),
+            text,
+        }
+    }
+}
+
+/// Store the bodies that are not stored yet
+///
+/// Bodies already stored are not compressed again, which matters for large responses that
+/// repeat across many requests.
+async fn insert_bodies(conn: &mut SqliteConnection, bodies: Vec<&Body>) -> Result<()> {
+    let mut missing: HashMap<&str, &Body> = bodies
+        .into_iter()
+        .map(|body| (body.hash.as_str(), body))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let mut query = QueryBuilder::new("SELECT hash FROM bodies WHERE hash IN (");
+    let mut hashes = query.separated(", ");
+    for hash in missing.keys() {
+        hashes.push_bind(*hash);
+    }
+    query.push(")");
+    let stored: Vec<String> = query.build_query_scalar().fetch_all(&mut *conn).await?;
+    for hash in &stored {
+        missing.remove(hash.as_str());
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let compressed = missing
+        .into_values()
+        .map(|body| {
+            Ok((
+                body,
+                zstd::encode_all(body.text.as_bytes(), COMPRESSION_LEVEL)?,
+            ))
         })
-        .filter_map(Result::ok);
+        .collect::<Result<Vec<_>>>()?;
 
-    query.push_values(iter, |mut b, (request_id, har_json)| {
-        b.push_bind(request_id.to_string())
-            .push_bind(har_json)
-            .push("strftime('%s','now')");
+    let mut query = QueryBuilder::new("INSERT INTO bodies (hash, data)");
+    query.push_values(&compressed, |mut b, (body, data)| {
+        b.push_bind(body.hash.as_str()).push_bind(data.as_slice());
     });
-
-    let query = query.build();
-    query.execute(&mut *conn).await?;
+    query.push(" ON CONFLICT (hash) DO NOTHING");
+    query.build().execute(&mut *conn).await?;
 
     Ok(())
 }
 
+/// Values copied from the HAR of a request into their own columns
+#[derive(Default)]
+struct Columns<'a> {
+    method: Option<&'a str>,
+    url: Option<&'a str>,
+    scheme: Option<&'a str>,
+    /// The authority of the URL, or the Host header if the URL is relative
+    host: Option<&'a str>,
+    /// The path of the URL without the query string
+    path: Option<&'a str>,
+    query: Option<&'a str>,
+    status: Option<i64>,
+    duration: Option<f64>,
+    request_body_size: Option<i64>,
+    response_body_size: Option<i64>,
+    request_content_type: Option<&'a str>,
+    response_content_type: Option<&'a str>,
+    upstream_address: Option<&'a str>,
+}
+
+impl<'a> Columns<'a> {
+    /// Extract the columns from the first entry
+    ///
+    /// The URL is split the same way as the migration that added the columns so stored and
+    /// migrated requests can be filtered alike.
+    fn from_har(har: &'a Har) -> Self {
+        let Some(entry) = har.entry() else {
+            return Columns::default();
+        };
+        let request = &entry.request;
+        let response = &entry.response;
+        let url = request.url.as_str();
+
+        let (scheme, authority_and_path) = match url.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), Some(rest)),
+            None => (None, None),
+        };
+        let (url_host, path_and_query) = match authority_and_path {
+            Some(rest) => match rest.find('/') {
+                Some(i) => (Some(&rest[..i]), &rest[i..]),
+                None => (Some(rest), "/"),
+            },
+            None => (None, url),
+        };
+        let path = path_and_query
+            .split_once('?')
+            .map_or(path_and_query, |(path, _)| path);
+
+        Columns {
+            method: Some(&request.method),
+            url: Some(url),
+            scheme,
+            host: url_host.or_else(|| header(&request.headers, &["host", ":authority"])),
+            path: Some(path),
+            query: url.split_once('?').map(|(_, query)| query),
+            status: Some(response.status),
+            duration: Some(entry.time),
+            request_body_size: Some(request.body_size),
+            response_body_size: Some(response.body_size),
+            request_content_type: request
+                .post_data
+                .as_ref()
+                .map(|post_data| post_data.mime_type.as_str())
+                .or_else(|| header(&request.headers, &["content-type"])),
+            response_content_type: response.content.mime_type.as_deref(),
+            upstream_address: entry.server_ip_address.as_deref(),
+        }
+    }
+}
+
+/// The value of the first header with one of the given lowercase names
+fn header<'a>(headers: &'a [Headers], names: &[&str]) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|header| names.contains(&header.name.to_ascii_lowercase().as_str()))
+        .map(|header| header.value.as_str())
+}
+
 pub async fn latest_request(pool: &SqlitePool) -> Result<Option<Har>> {
     tracing::trace!("latest_request");
     let mut conn = pool.acquire().await?;
 
-    let query = r#"
-        SELECT json(har)
+    let query = format!(
+        r#"
+        SELECT {}
         FROM requests
         ORDER BY request_id DESC
         LIMIT 1
-    "#;
+    "#,
+        HAR_COLUMNS
+    );
 
-    let row = sqlx::query(query)
+    let row = sqlx::query(&query)
         .fetch_optional(&mut *conn)
         .await
-        .map_err(|err| {
+        .inspect_err(|_| {
             tracing::error!("Failed to fetch latest request");
-            err
         })?;
 
-    let har_json: Option<Vec<u8>> = match row {
-        Some(row) => row.get(0),
-        None => return Ok(None),
-    };
+    match row {
+        Some(row) => har_from_row(&row),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_request(pool: &SqlitePool, request_id: Uuid) -> Result<Option<Har>> {
+    tracing::trace!("get_request");
+    let mut conn = pool.acquire().await?;
+
+    let query = format!(
+        r#"
+        SELECT {}
+        FROM requests
+        WHERE request_id = ?
+    "#,
+        HAR_COLUMNS
+    );
+
+    let row = sqlx::query(&query)
+        .bind(request_id.to_string())
+        .fetch_optional(&mut *conn)
+        .await
+        .inspect_err(|_| {
+            tracing::error!("Failed to fetch request");
+        })?;
+
+    match row {
+        Some(row) => har_from_row(&row),
+        None => Ok(None),
+    }
+}
+
+/// Delete a stored request
+///
+/// Returns `false` if no request with the given id exists.
+pub async fn delete_request(pool: &SqlitePool, request_id: Uuid) -> Result<bool> {
+    tracing::trace!("delete_request");
+    let mut conn = pool.acquire().await?;
+
+    let result = sqlx::query("DELETE FROM requests WHERE request_id = ?")
+        .bind(request_id.to_string())
+        .execute(&mut *conn)
+        .await
+        .inspect_err(|_| {
+            tracing::error!("Failed to delete request");
+        })?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Ids and query strings of the newest stored requests with a response and the given method
+/// and path
+///
+/// Either criterion is ignored when `None`. The path is compared exactly, without the query
+/// string. Only indexed columns are read so candidates can be narrowed down before their HARs
+/// are loaded.
+pub async fn find_requests(
+    pool: &SqlitePool,
+    method: Option<&str>,
+    path: Option<&str>,
+    limit: i64,
+) -> Result<Vec<(Uuid, Option<String>)>> {
+    tracing::trace!("find_requests");
+    let mut conn = pool.acquire().await?;
+
+    // Requests that never got a response are recorded with status 0 by browsers
+    let mut query = QueryBuilder::new("SELECT request_id, query FROM requests WHERE status >= 100");
+    if let Some(method) = method {
+        query.push(" AND method = ").push_bind(method.to_string());
+    }
+    if let Some(path) = path {
+        query.push(" AND path = ").push_bind(path.to_string());
+    }
+    query
+        .push(" ORDER BY request_id DESC LIMIT ")
+        .push_bind(limit);
+
+    let rows: Vec<(String, Option<String>)> = query
+        .build_query_as()
+        .fetch_all(&mut *conn)
+        .await
+        .inspect_err(|_| {
+            tracing::error!("Failed to find requests");
+        })?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|(request_id, query)| Some((Uuid::parse_str(&request_id).ok()?, query)))
+        .collect())
+}
+
+/// Ids of every stored request matching `filter` from oldest to newest
+pub async fn request_ids(pool: &SqlitePool, filter: &RequestFilter) -> Result<Vec<Uuid>> {
+    tracing::trace!("request_ids");
+    let mut conn = pool.acquire().await?;
+
+    let mut query = QueryBuilder::new("SELECT request_id FROM request_summaries");
+    filter.push_where(&mut query);
+    query.push(" ORDER BY request_id ASC");
+
+    let request_ids: Vec<String> = query
+        .build_query_scalar()
+        .fetch_all(&mut *conn)
+        .await
+        .inspect_err(|_| {
+            tracing::error!("Failed to fetch request ids");
+        })?;
+
+    Ok(request_ids
+        .iter()
+        .filter_map(|id| Uuid::parse_str(id).ok())
+        .collect())
+}
+
+/// The most requests read by one statement while streaming
+const STREAM_PAGE_SIZE: i64 = 100;
+
+/// Send every stored request matching `filter` to `tx` from oldest to newest
+///
+/// Requests are read a page at a time so the whole result set is never held in memory. Each
+/// page is read to the end before any of it is sent, so a slow receiver never keeps a read
+/// statement open and blocks the HAR writer. Returns early without an error if the receiver is
+/// dropped.
+pub async fn stream_requests(
+    pool: &SqlitePool,
+    filter: &RequestFilter,
+    tx: mpsc::Sender<Har>,
+) -> Result<()> {
+    tracing::trace!("stream_requests");
+    let mut after: Option<String> = None;
+
+    loop {
+        let mut query = QueryBuilder::new(format!(
+            "SELECT request_id, {} FROM request_summaries",
+            HAR_COLUMNS
+        ));
+        filter.push_where(&mut query);
+        if let Some(after) = &after {
+            query.push(" AND request_id > ").push_bind(after.clone());
+        }
+        query
+            .push(" ORDER BY request_id ASC LIMIT ")
+            .push_bind(STREAM_PAGE_SIZE);
+
+        let rows = query.build().fetch_all(pool).await.inspect_err(|_| {
+            tracing::error!("Failed to fetch requests");
+        })?;
+        let Some(last) = rows.last() else {
+            return Ok(());
+        };
+        after = Some(last.get("request_id"));
+
+        for row in &rows {
+            if let Some(har) = har_from_row(row)? {
+                if tx.send(har).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if rows.len() < STREAM_PAGE_SIZE as usize {
+            return Ok(());
+        }
+    }
+}
+
+/// Selects the HAR of a request along with its stored bodies for `har_from_row`
+const HAR_COLUMNS: &str = "json(har) AS har, \
+    (SELECT data FROM bodies WHERE hash = request_body) AS request_body_data, \
+    (SELECT data FROM bodies WHERE hash = response_body) AS response_body_data";
+
+/// Read a HAR selected with `HAR_COLUMNS`, putting its bodies back in place
+fn har_from_row(row: &SqliteRow) -> Result<Option<Har>> {
+    let har_json: Option<Vec<u8>> = row.get("har");
 
     if let Some(har_json) = har_json {
-        let har: Har = serde_json::from_slice(&har_json).map_err(|err| {
+        let mut har: Value = serde_json::from_slice(&har_json).inspect_err(|_| {
+            tracing::error!("Failed to deserialize HAR from JSON");
+        })?;
+        for (pointer, column) in [
+            (REQUEST_BODY_POINTER, "request_body_data"),
+            (RESPONSE_BODY_POINTER, "response_body_data"),
+        ] {
+            let data: Option<Vec<u8>> = row.get(column);
+            if let (Some(data), Some(text)) = (data, har.pointer_mut(pointer)) {
+                let text_bytes = zstd::decode_all(data.as_slice()).inspect_err(|_| {
+                    tracing::error!("Failed to decompress body");
+                })?;
+                *text = Value::String(String::from_utf8(text_bytes)?);
+            }
+        }
+        let har: Har = serde_json::from_value(har).inspect_err(|_| {
             tracing::error!("Failed to deserialize HAR from JSON");
-            err
         })?;
 
         Ok(Some(har))
@@ -123,3 +975,253 @@ pub async fn latest_request(pool: &SqlitePool) -> Result<Option<Har>> {
         Ok(None)
     }
 }
+
+/// A lightweight view of a stored request that does not include the HAR itself
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct RequestSummary {
+    pub request_id: String,
+    pub method: Option<String>,
+    pub url: Option<String>,
+    pub status: Option<i64>,
+    /// Total time of the transaction in milliseconds
+    pub duration: Option<f64>,
+    /// Unix timestamp in seconds
+    pub created_at: i64,
+}
+
+/// Criteria used to select stored requests
+///
+/// Every criterion that is set must match.
+#[derive(Debug, Default)]
+pub struct RequestFilter {
+    /// HTTP method, compared case-insensitively
+    pub method: Option<String>,
+
+    /// Path prefix, or a glob pattern if it contains `*`, `?` or `[`
+    pub path: Option<String>,
+
+    pub status: Option<StatusFilter>,
+
+    /// Host name with or without a port
+    pub host: Option<String>,
+
+    pub request_headers: Vec<HeaderFilter>,
+
+    pub response_headers: Vec<HeaderFilter>,
+
+    /// Inclusive lower bound of `created_at` as a unix timestamp in seconds
+    pub since: Option<i64>,
+
+    /// Exclusive upper bound of `created_at` as a unix timestamp in seconds
+    pub until: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StatusFilter {
+    /// An exact status code such as 404
+    Code(u16),
+
+    /// A status class such as 5 for 5xx
+    Class(u16),
+}
+
+#[derive(Debug)]
+pub struct HeaderFilter {
+    pub name: String,
+
+    /// Only check that the header is present when `None`
+    pub value: Option<String>,
+}
+
+impl RequestFilter {
+    /// Parse the query parameters that select stored requests
+    ///
+    /// - `method`: HTTP method
+    /// - `path`: path prefix or glob pattern
+    /// - `status`: status code such as `404` or class such as `5xx`
+    /// - `host`: host name with or without a port
+    /// - `request_header`, `response_header`: `name` to require a header or `name:value` to match its
+    ///   value. May be repeated.
+    /// - `since`, `until`: unix timestamp in seconds or RFC 3339 date time
+    pub fn from_query(query: &str) -> Result<Self, &'static str> {
+        let mut filter = RequestFilter::default();
+
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "method" => filter.method = Some(value.into_owned()),
+                "path" => filter.path = Some(value.into_owned()),
+                "host" => filter.host = Some(value.into_owned()),
+                "status" => filter.status = Some(parse_status(&value).ok_or("Invalid status")?),
+                "request_header" => filter.request_headers.push(parse_header(&value)),
+                "response_header" => filter.response_headers.push(parse_header(&value)),
+                "since" => filter.since = Some(parse_timestamp(&value).ok_or("Invalid since")?),
+                "until" => filter.until = Some(parse_timestamp(&value).ok_or("Invalid until")?),
+                _ => {}
+            }
+        }
+
+        Ok(filter)
+    }
+
+    fn push_where(&self, query: &mut QueryBuilder<'_, Sqlite>) {
+        query.push(" WHERE 1 = 1");
+
+        if let Some(method) = &self.method {
+            query
+                .push(" AND method = ")
+                .push_bind(method.to_ascii_uppercase());
+        }
+
+        if let Some(path) = &self.path {
+            if path.contains(['*', '?', '[']) {
+                query.push(" AND path GLOB ").push_bind(path.clone());
+            } else {
+                // A prefix without wildcards lets GLOB use the index on path
+                query
+                    .push(" AND path GLOB ")
+                    .push_bind(format!("{}*", path));
+            }
+        }
+
+        match self.status {
+            Some(StatusFilter::Code(code)) => {
+                query.push(" AND status = ").push_bind(code as i64);
+            }
+            Some(StatusFilter::Class(class)) => {
+                query
+                    .push(" AND status >= ")
+                    .push_bind(class as i64 * 100)
+                    .push(" AND status < ")
+                    .push_bind((class as i64 + 1) * 100);
+            }
+            None => {}
+        }
+
+        if let Some(host) = &self.host {
+            // Hosts with any port sort between `host:` and `host;`
+            let host = host.to_lowercase();
+            query
+                .push(" AND (lower(host) = ")
+                .push_bind(host.clone())
+                .push(" OR (lower(host) >= ")
+                .push_bind(format!("{}:", host))
+                .push(" AND lower(host) < ")
+                .push_bind(format!("{};", host))
+                .push("))");
+        }
+
+        for (side, headers) in [
+            ("request", &self.request_headers),
+            ("response", &self.response_headers),
+        ] {
+            for header in headers {
+                query
+                    .push(
+                        " AND EXISTS (SELECT 1 FROM request_headers AS h \
+                        WHERE h.request_id = request_summaries.request_id AND h.side = ",
+                    )
+                    .push_bind(side)
+                    .push(" AND lower(h.name) = lower(")
+                    .push_bind(header.name.clone())
+                    .push(")");
+                if let Some(value) = &header.value {
+                    query.push(" AND h.value = ").push_bind(value.clone());
+                }
+                query.push(")");
+            }
+        }
+
+        if let Some(since) = self.since {
+            query.push(" AND created_at >= ").push_bind(since);
+        }
+
+        if let Some(until) = self.until {
+            query.push(" AND created_at < ").push_bind(until);
+        }
+    }
+}
+
+fn parse_status(value: &str) -> Option<StatusFilter> {
+    let value = value.to_ascii_lowercase();
+    if let Some(class) = value.strip_suffix("xx") {
+        match class.parse::<u16>() {
+            Ok(class) if (1..=5).contains(&class) => Some(StatusFilter::Class(class)),
+            _ => None,
+        }
+    } else {
+        match value.parse::<u16>() {
+            Ok(code) if (100..=599).contains(&code) => Some(StatusFilter::Code(code)),
+            _ => None,
+        }
+    }
+}
+
+fn parse_header(value: &str) -> HeaderFilter {
+    match value.split_once(':') {
+        Some((name, value)) => HeaderFilter {
+            name: name.trim().to_string(),
+            value: Some(value.trim().to_string()),
+        },
+        None => HeaderFilter {
+            name: value.trim().to_string(),
+            value: None,
+        },
+    }
+}
+
+fn parse_timestamp(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|d| d.timestamp())
+    })
+}
+
+/// List stored requests matching `filter` from newest to oldest
+///
+/// Pass the `request_id` of the last summary from a previous page as `before` to fetch the next
+/// page. UUIDv7 ids sort in creation order so they double as a stable cursor.
+pub async fn list_requests(
+    pool: &SqlitePool,
+    filter: &RequestFilter,
+    before: Option<Uuid>,
+    limit: i64,
+) -> Result<Vec<RequestSummary>> {
+    tracing::trace!("list_requests");
+    let mut conn = pool.acquire().await?;
+
+    let mut query = QueryBuilder::new(
+        r#"
+        SELECT
+            request_id,
+            method,
+            url,
+            status,
+            duration,
+            created_at
+        FROM request_summaries
+        "#,
+    );
+
+    filter.push_where(&mut query);
+
+    if let Some(before) = before {
+        query
+            .push(" AND request_id < ")
+            .push_bind(before.to_string());
+    }
+
+    query
+        .push(" ORDER BY request_id DESC LIMIT ")
+        .push_bind(limit);
+
+    let summaries = query
+        .build_query_as::<RequestSummary>()
+        .fetch_all(&mut *conn)
+        .await
+        .inspect_err(|_| {
+            tracing::error!("Failed to list requests");
+        })?;
+
+    Ok(summaries)
+}
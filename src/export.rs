@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use har::v1_3::{Creator, Entries, PageTimings, Pages};
+use hyper::body::Bytes;
+use sqlx::sqlite::SqlitePool;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::config;
+use crate::db;
+use crate::har::CustomFields;
+
+/// Write every stored request matching `filter` to `writer` as a single HAR document
+///
+/// `filter` uses the same query string syntax as `GET /requests`.
+pub async fn export<W: AsyncWrite + Unpin>(
+    config: &config::Config,
+    filter: &str,
+    pages: bool,
+    mut writer: W,
+) -> Result<()> {
+    let db = db::init_db(&config.database).await?;
+    let filter = db::RequestFilter::from_query(filter).map_err(anyhow::Error::msg)?;
+
+    let mut chunks = stream(db, filter, pages);
+    while let Some(chunk) = chunks.next().await {
+        writer.write_all(&chunk?).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Merge every stored request matching `filter` into a single HAR log
+///
+/// The document is produced in chunks as rows are read from the database. When `pages` is set,
+/// entries are grouped into one page per host.
+pub fn stream(
+    db: SqlitePool,
+    filter: db::RequestFilter,
+    pages: bool,
+) -> ReceiverStream<Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let (har_tx, mut har_rx) = mpsc::channel(16);
+        let reader = tokio::spawn(async move { db::stream_requests(&db, &filter, har_tx).await });
+
+        let mut writer = LogWriter::new(pages);
+        if tx.send(writer.start()).await.is_err() {
+            return;
+        }
+
+        while let Some(har) = har_rx.recv().await {
+            for (entry, custom) in har.into_entries() {
+                if tx.send(writer.entry(entry, custom)).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        // Report a failure to read from the database instead of ending with a truncated document
+        match reader.await {
+            Ok(Ok(())) => {
+                let _ = tx.send(writer.finish()).await;
+            }
+            Ok(Err(err)) => {
+                tracing::error!("Error while exporting requests: {}", err);
+                let _ = tx.send(Err(err)).await;
+            }
+            Err(err) => {
+                tracing::error!("Export task failed: {}", err);
+                let _ = tx.send(Err(err.into())).await;
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+/// Serializes a HAR log one entry at a time
+struct LogWriter {
+    entries: usize,
+    pages: Option<Vec<Pages>>,
+    page_ids: HashMap<String, String>,
+}
+
+impl LogWriter {
+    fn new(pages: bool) -> Self {
+        LogWriter {
+            entries: 0,
+            pages: pages.then(Vec::new),
+            page_ids: HashMap::new(),
+        }
+    }
+
+    fn start(&self) -> Result<Bytes> {
+        let creator = serde_json::to_string(&Creator {
+            name: "park".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            comment: None,
+        })?;
+
+        // Entries are written with the `har::v1_3` schema
+        Ok(Bytes::from(format!(
+            r#"{{"log":{{"version":"1.3","creator":{},"entries":["#,
+            creator
+        )))
+    }
+
+    fn entry(&mut self, mut entry: Entries, custom: CustomFields) -> Result<Bytes> {
+        if let Some(pages) = self.pages.as_mut() {
+            let host = entry_host(&entry);
+            let id = match self.page_ids.get(&host) {
+                Some(id) => id.clone(),
+                None => {
+                    // Entries are written from oldest to newest so the first entry seen for a
+                    // host marks the start of its page
+                    let id = format!("page_{}", pages.len() + 1);
+                    pages.push(Pages {
+                        started_date_time: entry.started_date_time.clone(),
+                        id: id.clone(),
+                        title: host.clone(),
+                        page_timings: PageTimings {
+                            on_content_load: Some(-1.0),
+                            on_load: Some(-1.0),
+                            comment: None,
+                        },
+                        comment: None,
+                    });
+                    self.page_ids.insert(host, id.clone());
+                    id
+                }
+            };
+            entry.pageref = Some(id);
+        }
+
+        let mut chunk = if self.entries == 0 {
+            Vec::new()
+        } else {
+            b",".to_vec()
+        };
+        let mut entry = serde_json::to_value(&entry)?;
+        if let Some(entry) = entry.as_object_mut() {
+            entry.extend(custom);
+        }
+        serde_json::to_writer(&mut chunk, &entry)?;
+        self.entries += 1;
+
+        Ok(Bytes::from(chunk))
+    }
+
+    fn finish(&mut self) -> Result<Bytes> {
+        let mut chunk = b"]".to_vec();
+        if let Some(pages) = self.pages.take() {
+            chunk.extend_from_slice(br#","pages":"#);
+            serde_json::to_writer(&mut chunk, &pages)?;
+        }
+        chunk.extend_from_slice(b"}}");
+
+        Ok(Bytes::from(chunk))
+    }
+}
+
+/// Host the entry was sent to, taken from an absolute URL or the `Host` header
+fn entry_host(entry: &Entries) -> String {
+    if let Ok(url) = url::Url::parse(&entry.request.url) {
+        if url.has_host() {
+            return url[url::Position::BeforeHost..url::Position::AfterPort].to_string();
+        }
+    }
+
+    entry
+        .request
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("host") || h.name == ":authority")
+        .map(|h| h.value.clone())
+        .unwrap_or_default()
+}
@@ -1,34 +1,360 @@
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
 use anyhow::{anyhow, Result};
-use har::v1_3::{Cache, Content, Creator, Entries, Log, PostData, Request, Response, Timings};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::future::join;
+use har::v1_3::{
+    Cache, Content, Cookies, Creator, Entries, Headers, Log, PostData, QueryString, Request,
+    Response, Timings,
+};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::body::Bytes;
-use serde::{Deserialize, Serialize};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+/// Fields of an entry that are not part of the HAR spec, such as Chrome's `_webSocketMessages`
+///
+/// Custom fields start with an underscore.
+pub type CustomFields = Map<String, Value>;
+
+/// A HAR log as park stores it, without the `{"log": ...}` wrapper
+///
+/// `har::v1_3::Entries` has no room for custom fields so they are kept next to the log and
+/// written back into their entry when serialized.
+#[derive(Clone, Debug)]
+pub struct Har {
+    log: Log,
+
+    /// Custom fields of each entry, in the same order as `log.entries`
+    custom: Vec<CustomFields>,
+}
+
+impl Serialize for Har {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut log = serde_json::to_value(&self.log).map_err(ser::Error::custom)?;
+        if let Some(entries) = log.get_mut("entries").and_then(Value::as_array_mut) {
+            for (entry, custom) in entries.iter_mut().zip(self.custom.iter()) {
+                if let Some(entry) = entry.as_object_mut() {
+                    entry.extend(custom.clone());
+                }
+            }
+        }
+
+        log.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Har {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut log = Value::deserialize(deserializer)?;
+        let custom = log
+            .get_mut("entries")
+            .and_then(Value::as_array_mut)
+            .map(|entries| entries.iter_mut().map(take_custom_fields).collect())
+            .unwrap_or_default();
+        let log = Log::deserialize(log).map_err(de::Error::custom)?;
+
+        Ok(Har { log, custom })
+    }
+}
+
+/// Remove the fields starting with an underscore from a serialized entry
+fn take_custom_fields(entry: &mut Value) -> CustomFields {
+    let Some(entry) = entry.as_object_mut() else {
+        return CustomFields::new();
+    };
+
+    let names = entry
+        .keys()
+        .filter(|name| name.starts_with('_'))
+        .cloned()
+        .collect::<Vec<_>>();
+    names
+        .into_iter()
+        .filter_map(|name| entry.remove(&name).map(|value| (name, value)))
+        .collect()
+}
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Har(Log);
+/// A WebSocket frame in the form Chrome records it in the `_webSocketMessages` field of an entry
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WebSocketMessage {
+    /// `send` for frames from the client and `receive` for frames from the server
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    /// Unix timestamp in seconds of when the frame was received by the proxy
+    pub time: f64,
+
+    pub opcode: u8,
+
+    /// The payload of text frames as is and of every other frame base64 encoded
+    pub data: String,
+}
+
+/// What went through a CONNECT tunnel, recorded in the `_tunnel` field of an entry
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tunnel {
+    /// Bytes sent from the client to the target
+    pub bytes_sent: u64,
+
+    /// Bytes sent from the target to the client
+    pub bytes_received: u64,
+
+    /// Why the tunnel was closed, such as `client closed` or `idle timeout`
+    pub close_reason: String,
+}
+
+/// A HAR queued for storage along with the id it will be stored under
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Recording {
+    pub request_id: Uuid,
+    pub har: Har,
+
+    /// Unix timestamp in seconds of when the transaction started
+    pub created_at: i64,
+}
+
+impl Recording {
+    /// Split a HAR log into one recording per entry
+    ///
+    /// The id and `created_at` of each recording are derived from the entry's
+    /// `startedDateTime` so imported entries sort alongside recordings made by the proxy.
+    /// Entries without a valid start time are recorded as starting now.
+    pub fn split(har: Har) -> Vec<Recording> {
+        let Har { log, custom } = har;
+        let Log {
+            creator,
+            browser,
+            pages,
+            entries,
+            comment,
+        } = log;
+
+        entries
+            .into_iter()
+            .zip(custom)
+            .map(|(entry, custom)| {
+                let started = DateTime::parse_from_rfc3339(&entry.started_date_time)
+                    .map(|d| d.to_utc())
+                    .unwrap_or_else(|_| Utc::now());
+                let timestamp = uuid::Timestamp::from_unix(
+                    uuid::NoContext,
+                    started.timestamp().max(0) as u64,
+                    started.timestamp_subsec_nanos(),
+                );
+
+                // Keep the page the entry belongs to so no information is lost
+                let pages = pages.as_ref().map(|pages| {
+                    pages
+                        .iter()
+                        .filter(|page| entry.pageref.as_ref() == Some(&page.id))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                });
+
+                Recording {
+                    request_id: Uuid::new_v7(timestamp),
+                    har: Har {
+                        log: Log {
+                            creator: creator.clone(),
+                            browser: browser.clone(),
+                            pages,
+                            entries: vec![entry],
+                            comment: comment.clone(),
+                        },
+                        custom: vec![custom],
+                    },
+                    created_at: started.timestamp(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A HAR document as written by browsers and other tools
+///
+/// Unlike `Har`, the log is wrapped in a `log` object.
+#[derive(Debug, Deserialize)]
+pub struct HarFile {
+    pub log: Har,
+}
+
+/// Points in time recorded by the proxy while handling a single transaction
+///
+/// The end of the response body is not part of this struct. It is observed by
+/// `Har::from_transaction` when the body stream finishes.
+pub struct Timing {
+    /// Wall clock time the downstream request arrived
+    pub started_date_time: DateTime<Utc>,
+
+    /// When the downstream request arrived
+    pub started: Instant,
+
+    /// When the request was handed to the upstream client
+    pub request_sent: Instant,
+
+    /// When the last request body chunk was handed to the upstream client
+    ///
+    /// Set by the task forwarding the request body, which finishes before the recorded copy of
+    /// the body ends. Transactions without a forwarded body fall back to the end of the recorded
+    /// body.
+    pub request_body_sent: Arc<OnceLock<Instant>>,
+
+    /// When the upstream response headers arrived
+    pub response_started: Instant,
+}
+
+impl Timing {
+    pub fn start() -> Self {
+        let now = Instant::now();
+
+        Timing {
+            started_date_time: Utc::now(),
+            started: now,
+            request_sent: now,
+            request_body_sent: Arc::default(),
+            response_started: now,
+        }
+    }
+}
 
 impl Har {
+    pub fn new(log: Log) -> Self {
+        let custom = vec![CustomFields::new(); log.entries.len()];
+        Har { log, custom }
+    }
+
+    pub fn into_log(self) -> Log {
+        self.log
+    }
+
+    /// Every entry along with its custom fields
+    pub fn into_entries(self) -> impl Iterator<Item = (Entries, CustomFields)> {
+        self.log.entries.into_iter().zip(self.custom)
+    }
+
+    /// The first entry
+    pub fn entry(&self) -> Option<&Entries> {
+        self.log.entries.first()
+    }
+
+    /// The request of the first entry
+    pub fn request(&self) -> Option<&Request> {
+        self.log.entries.first().map(|entry| &entry.request)
+    }
+
+    /// The request of the first entry, for changing it before it is replayed
+    pub fn request_mut(&mut self) -> Option<&mut Request> {
+        self.log.entries.first_mut().map(|entry| &mut entry.request)
+    }
+
+    /// Set the comment of the first entry
+    pub fn set_comment(&mut self, comment: String) {
+        if let Some(entry) = self.log.entries.first_mut() {
+            entry.comment = Some(comment);
+        }
+    }
+
+    /// Set the IP address of the upstream server the first entry was sent to
+    pub fn set_server_ip_address(&mut self, ip: IpAddr) {
+        if let Some(entry) = self.log.entries.first_mut() {
+            entry.server_ip_address = Some(ip.to_string());
+        }
+    }
+
+    /// Record the frames of a WebSocket connection on the first entry
+    ///
+    /// `omitted` is the number of frames left out because the connection went past the
+    /// recording limits. It is recorded in `_webSocketMessagesOmitted` when not zero.
+    pub fn set_web_socket_messages(
+        &mut self,
+        messages: Vec<WebSocketMessage>,
+        omitted: u64,
+    ) -> Result<()> {
+        if let Some(custom) = self.custom.first_mut() {
+            custom.insert("_resourceType".to_string(), "websocket".into());
+            custom.insert(
+                "_webSocketMessages".to_string(),
+                serde_json::to_value(messages)?,
+            );
+            if omitted > 0 {
+                custom.insert("_webSocketMessagesOmitted".to_string(), omitted.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the traffic of a CONNECT tunnel that was open for `duration` on the first entry
+    pub fn set_tunnel(&mut self, tunnel: Tunnel, duration: Duration) -> Result<()> {
+        if let (Some(entry), Some(custom)) = (self.log.entries.first_mut(), self.custom.first_mut())
+        {
+            // The tunnel is the body of the response as far as timings are concerned
+            entry.time += millis(duration);
+            entry.timings.receive += millis(duration);
+            entry.request.body_size = tunnel.bytes_sent as i64;
+            entry.response.body_size = tunnel.bytes_received as i64;
+            entry.response.content.size = tunnel.bytes_received as i64;
+            entry.comment = Some(format!("tunnel {}", tunnel.close_reason));
+            custom.insert("_tunnel".to_string(), serde_json::to_value(tunnel)?);
+        }
+
+        Ok(())
+    }
+
+    /// The response of the first entry
+    pub fn response(&self) -> Option<&Response> {
+        self.log.entries.first().map(|entry| &entry.response)
+    }
+
     pub async fn from_transaction<T: BodyExt, U: BodyExt>(
         req: hyper::Request<T>,
         resp: hyper::Response<U>,
+        timing: Timing,
     ) -> Self {
         let (req, req_body) = req.into_parts();
-        let req_text = body_to_string(req_body).await;
-
         let (res, res_body) = resp.into_parts();
-        let res_text = body_to_string(res_body).await;
+
+        // Both bodies are streamed at the same time so they must be collected concurrently
+        let ((req_body, request_finished), (res_body, response_finished)) = join(
+            async { (body_to_text(req_body).await, Instant::now()) },
+            async { (body_to_text(res_body).await, Instant::now()) },
+        )
+        .await;
+
+        // The upstream client starts sending before the request body has been fully read from the
+        // downstream client, so the send phase ends with the last request body chunk and the
+        // server only starts working on the response after that.
+        let request_body_sent = timing
+            .request_body_sent
+            .get()
+            .copied()
+            .unwrap_or(request_finished);
+        let blocked = timing.request_sent.duration_since(timing.started);
+        let send = request_body_sent.saturating_duration_since(timing.request_sent);
+        let wait = timing
+            .response_started
+            .saturating_duration_since(request_body_sent);
+        let receive = response_finished.saturating_duration_since(timing.response_started);
 
         let entry = Entries {
             pageref: None,
-            started_date_time: "".to_string(),
-            time: 0.0,
+            started_date_time: timing
+                .started_date_time
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            time: millis(blocked + send + wait + receive),
             request: Request {
                 method: req.method.as_str().to_string(),
                 url: req.uri.to_string(),
                 http_version: display_version(req.version),
-                cookies: vec![],
-                headers: vec![],
-                query_string: vec![],
+                cookies: request_cookies(&req.headers),
+                headers: headers_to_har(&req.headers),
+                query_string: query_string(&req.uri),
                 post_data: Some(PostData {
                     mime_type: req
                         .headers
@@ -36,13 +362,13 @@ impl Har {
                         .map(|v| v.to_str().unwrap_or("application/octet-stream"))
                         .unwrap_or("application/octet-stream")
                         .to_string(),
-                    text: req_text,
+                    text: req_body.as_ref().map(|b| b.text.clone()),
                     params: None,
                     comment: None,
-                    encoding: None,
+                    encoding: req_body.as_ref().and_then(|b| b.encoding.clone()),
                 }),
-                headers_size: 0,
-                body_size: 0,
+                headers_size: request_headers_size(&req),
+                body_size: req_body.as_ref().map(|b| b.size).unwrap_or(-1),
                 comment: None,
                 headers_compression: None,
             },
@@ -54,10 +380,10 @@ impl Har {
                     .unwrap_or_default()
                     .to_string(),
                 http_version: display_version(res.version),
-                cookies: vec![],
-                headers: vec![],
+                cookies: response_cookies(&res.headers),
+                headers: headers_to_har(&res.headers),
                 content: Content {
-                    size: 0,
+                    size: res_body.as_ref().map(|b| b.size).unwrap_or(-1),
                     compression: None,
                     mime_type: Some(
                         res.headers
@@ -66,13 +392,13 @@ impl Har {
                             .unwrap_or("application/octet-stream")
                             .to_string(),
                     ),
-                    text: res_text,
-                    encoding: None,
+                    text: res_body.as_ref().map(|b| b.text.clone()),
+                    encoding: res_body.as_ref().and_then(|b| b.encoding.clone()),
                     comment: None,
                 },
                 redirect_url: None,
-                headers_size: 0,
-                body_size: 0,
+                headers_size: response_headers_size(&res),
+                body_size: res_body.as_ref().map(|b| b.size).unwrap_or(-1),
                 comment: None,
                 headers_compression: None,
             },
@@ -80,14 +406,16 @@ impl Har {
                 before_request: None,
                 after_request: None,
             },
+            // reqwest does not expose when a pooled connection was resolved, connected or
+            // negotiated TLS so those phases are recorded as not applicable
             timings: Timings {
-                blocked: None,
-                dns: None,
-                connect: None,
-                send: 0.0,
-                wait: 0.0,
-                receive: 0.0,
-                ssl: None,
+                blocked: Some(millis(blocked)),
+                dns: Some(-1.0),
+                connect: Some(-1.0),
+                send: millis(send),
+                wait: millis(wait),
+                receive: millis(receive),
+                ssl: Some(-1.0),
                 comment: None,
             },
             server_ip_address: None,
@@ -107,7 +435,7 @@ impl Har {
             comment: None,
         };
 
-        Har(log)
+        Har::new(log)
     }
 }
 
@@ -115,30 +443,58 @@ impl TryFrom<Har> for hyper::Request<BoxBody<Bytes, std::convert::Infallible>> {
     type Error = anyhow::Error;
 
     fn try_from(mut har: Har) -> Result<Self> {
-        if har.0.entries.len() != 1 {
+        if har.log.entries.len() != 1 {
             return Err(anyhow!("Expected exactly one entry in HAR log"));
         }
 
         let request = har
-            .0
+            .log
             .entries
             .pop()
             .expect("Expected exactly one entry in HAR log")
             .request;
 
+        // HAR files from other tools may only describe the query string and cookies in their
+        // structured form, so rebuild them when the URL and headers do not carry them
+        let mut url = request.url.clone();
+        if !url.contains('?') && !request.query_string.is_empty() {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(request.query_string.iter().map(|q| (&q.name, &q.value)))
+                .finish();
+            url = format!("{}?{}", url, query);
+        }
+
         let mut req = hyper::Request::builder()
             .method(request.method.as_str())
-            .uri(request.url.as_str());
+            .uri(url.as_str());
 
+        // HTTP/2 exports such as Chrome's list pseudo-headers like `:authority`, which are not
+        // valid header names. The method and URL already carry what they describe
         for header in request.headers.iter() {
+            if header.name.starts_with(':') {
+                continue;
+            }
             req = req.header(header.name.as_str(), header.value.as_str());
         }
 
+        let has_cookie_header = request
+            .headers
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case("cookie"));
+        if !has_cookie_header && !request.cookies.is_empty() {
+            let cookie = request
+                .cookies
+                .iter()
+                .map(|c| format!("{}={}", c.name, c.value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            req = req.header(http::header::COOKIE, cookie);
+        }
+
         let body: BoxBody<Bytes, std::convert::Infallible> = match request.post_data {
-            Some(post_data) => match post_data.text {
-                Some(text) => Full::new(Bytes::from(text)).boxed(),
-                None => Full::new(Bytes::new()).boxed(),
-            },
+            Some(post_data) => {
+                Full::new(decode_text(post_data.text, post_data.encoding.as_deref())?).boxed()
+            }
             None => Full::new(Bytes::new()).boxed(),
         };
 
@@ -146,53 +502,200 @@ impl TryFrom<Har> for hyper::Request<BoxBody<Bytes, std::convert::Infallible>> {
     }
 }
 
+/// Decode the `text` of a HAR body into the original bytes
+pub fn decode_text(text: Option<String>, encoding: Option<&str>) -> Result<Bytes> {
+    match (text, encoding) {
+        (Some(text), Some("base64")) => Ok(Bytes::from(BASE64.decode(text)?)),
+        (Some(_), Some(encoding)) => Err(anyhow!("Unsupported body encoding: {}", encoding)),
+        (Some(text), None) => Ok(Bytes::from(text)),
+        (None, _) => Ok(Bytes::new()),
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
 fn display_version(v: http::Version) -> String {
     format!("{:?}", v)
 }
 
-async fn body_to_string<T: BodyExt>(body: T) -> Option<String> {
-    body.collect()
-        .await
-        .map(|b| {
-            let bytes = b.to_bytes();
-            String::from_utf8(bytes.to_vec())
-                .map_err(|e| {
-                    tracing::error!("Error converting request body to string: {:?}", e);
-                    e
+/// Convert a header map into HAR headers
+///
+/// Every value of a multi-valued header is written as its own entry. Names are written in the
+/// lowercase form `http::HeaderMap` stores them in because hyper does not expose the original
+/// case captured by `preserve_header_case`.
+fn headers_to_har(headers: &http::HeaderMap) -> Vec<Headers> {
+    headers
+        .iter()
+        .map(|(name, value)| Headers {
+            name: name.as_str().to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            comment: None,
+        })
+        .collect()
+}
+
+/// Decode the query string of a request URI into HAR query string entries
+fn query_string(uri: &http::Uri) -> Vec<QueryString> {
+    uri.query()
+        .map(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .map(|(name, value)| QueryString {
+                    name: name.into_owned(),
+                    value: value.into_owned(),
+                    comment: None,
                 })
-                .unwrap_or_default()
+                .collect()
         })
-        .map_err(|e| {
-            tracing::error!("Error collecting request body");
-            e
+        .unwrap_or_default()
+}
+
+/// Parse every `Cookie` request header into HAR cookies
+fn request_cookies(headers: &http::HeaderMap) -> Vec<Cookies> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some(Cookies {
+                name: name.trim().to_string(),
+                value: value.trim().to_string(),
+                ..Default::default()
+            })
         })
-        .ok()
+        .collect()
 }
 
-pub mod writer {
-    use sqlx::sqlite::SqlitePool;
-    use tokio::sync::mpsc;
+/// Parse every `Set-Cookie` response header into HAR cookies
+fn response_cookies(headers: &http::HeaderMap) -> Vec<Cookies> {
+    headers
+        .get_all(http::header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(parse_set_cookie)
+        .collect()
+}
 
-    use crate::db;
-    use crate::har::Har;
+/// Parse a single `Set-Cookie` header value
+///
+/// See https://www.rfc-editor.org/rfc/rfc6265#section-5.2
+fn parse_set_cookie(value: &str) -> Option<Cookies> {
+    let mut parts = value.split(';');
+    let (name, value) = parts.next()?.trim().split_once('=')?;
 
-    pub async fn queue(db: SqlitePool) -> mpsc::Sender<Har> {
-        let mut buffer: Vec<Har> = Vec::with_capacity(1000);
-        let (tx, mut rx) = mpsc::channel(1000);
-        tokio::spawn(async move {
-            loop {
-                let count = rx.recv_many(&mut buffer, 100).await;
-                if count == 0 {
-                    tracing::debug!("har writer channel has been closed");
-                    return;
-                }
+    let mut cookie = Cookies {
+        name: name.trim().to_string(),
+        value: value.trim().to_string(),
+        ..Default::default()
+    };
+    let mut max_age = None;
 
-                let _ = db::insert_request(&db, &mut buffer).await.map_err(|e| {
-                    tracing::error!("Error while saving HAR: {}", e);
-                });
+    for attribute in parts {
+        let (key, value) = match attribute.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attribute.trim(), ""),
+        };
+
+        match key.to_ascii_lowercase().as_str() {
+            "path" => cookie.path = Some(value.to_string()),
+            "domain" => cookie.domain = Some(value.to_string()),
+            "expires" => {
+                // HAR expects ISO 8601 while cookies use an HTTP-date
+                cookie.expires = Some(
+                    DateTime::parse_from_rfc2822(value)
+                        .map(|d| d.to_utc().to_rfc3339_opts(SecondsFormat::Secs, true))
+                        .unwrap_or_else(|_| value.to_string()),
+                );
             }
-        });
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "httponly" => cookie.http_only = Some(true),
+            "secure" => cookie.secure = Some(true),
+            _ => {}
+        }
+    }
 
-        tx
+    // Max-Age takes precedence over Expires
+    if let Some(expires) = max_age
+        .and_then(chrono::TimeDelta::try_seconds)
+        .and_then(|max_age| Utc::now().checked_add_signed(max_age))
+    {
+        cookie.expires = Some(expires.to_rfc3339_opts(SecondsFormat::Secs, true));
     }
+
+    Some(cookie)
+}
+
+/// Size in bytes of the header block as it would appear in an HTTP/1.1 message
+///
+/// Each header line is `name: value\r\n` and the block is terminated by an empty line.
+fn header_block_size(headers: &http::HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + 2 + value.len() + 2)
+        .sum::<usize>()
+        + 2
+}
+
+/// Number of bytes from the start of the request line up to and including the blank line
+/// before the body
+fn request_headers_size(req: &http::request::Parts) -> i64 {
+    let target = req
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    // `METHOD SP target SP HTTP/x.y CRLF`
+    let request_line = req.method.as_str().len() + 1 + target.len() + 1 + 8 + 2;
+
+    (request_line + header_block_size(&req.headers)) as i64
+}
+
+/// Number of bytes from the start of the status line up to and including the blank line before
+/// the body
+fn response_headers_size(res: &http::response::Parts) -> i64 {
+    // `HTTP/x.y SP 3DIGIT SP reason CRLF`
+    let status_line = 8 + 1 + 3 + 1 + res.status.canonical_reason().unwrap_or_default().len() + 2;
+
+    (status_line + header_block_size(&res.headers)) as i64
+}
+
+/// A collected body in the form it is stored in a HAR file
+struct BodyText {
+    text: String,
+
+    /// `Some("base64")` when the body is not valid UTF-8
+    encoding: Option<String>,
+
+    /// Size of the body in bytes
+    size: i64,
+}
+
+async fn body_to_text<T: BodyExt>(body: T) -> Option<BodyText> {
+    body.collect()
+        .await
+        .map(|b| {
+            let bytes = b.to_bytes();
+            let size = bytes.len() as i64;
+            match std::str::from_utf8(&bytes) {
+                Ok(text) => BodyText {
+                    text: text.to_string(),
+                    encoding: None,
+                    size,
+                },
+                Err(_) => BodyText {
+                    text: BASE64.encode(&bytes),
+                    encoding: Some("base64".to_string()),
+                    size,
+                },
+            }
+        })
+        .inspect_err(|_| {
+            tracing::error!("Error collecting request body");
+        })
+        .ok()
 }
+
+pub mod writer;
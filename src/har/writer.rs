@@ -0,0 +1,495 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::config::{self, Overflow};
+use crate::db;
+use crate::har::Recording;
+
+/// The most recordings written to the database in one statement
+const BATCH_SIZE: usize = 100;
+
+/// The delay before retrying a batch the first time. It doubles for every further retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Queues recordings for the writer task
+///
+/// The writer task finishes once every clone has been dropped and the queue has been drained.
+pub struct HarQueue {
+    shared: Arc<Shared>,
+}
+
+impl HarQueue {
+    /// Queue a recording, applying the overflow policy when the queue is full
+    ///
+    /// Fails once the writer has been closed.
+    pub async fn send(&self, recording: Recording) -> Result<()> {
+        let shared = &self.shared;
+
+        loop {
+            let dequeued = shared.dequeued.notified();
+            tokio::pin!(dequeued);
+            // Register for wakeups before checking for room so a batch taken in between is not
+            // missed
+            dequeued.as_mut().enable();
+
+            if shared.closed.load(Ordering::Acquire) {
+                return Err(anyhow!("HAR queue is closed"));
+            }
+
+            {
+                let mut recordings = shared.recordings.lock().unwrap();
+                if recordings.len() < shared.capacity {
+                    recordings.push_back(recording);
+                    shared.queued.notify_one();
+                    return Ok(());
+                }
+
+                match shared.overflow {
+                    Overflow::Block => {}
+                    Overflow::DropOldest => {
+                        recordings.pop_front();
+                        recordings.push_back(recording);
+                        shared.queued.notify_one();
+                        shared.dropped(1);
+                        return Ok(());
+                    }
+                    Overflow::DropNewest => {
+                        shared.dropped(1);
+                        return Ok(());
+                    }
+                    Overflow::Spill => {}
+                }
+            }
+
+            if shared.overflow == Overflow::Spill {
+                return shared.spill(&[recording]).await;
+            }
+
+            dequeued.await;
+        }
+    }
+
+    /// Counters of the recordings that did not go straight from the queue to the database
+    pub fn stats(&self) -> QueueStats {
+        self.shared.stats()
+    }
+}
+
+impl Clone for HarQueue {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+
+        HarQueue {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for HarQueue {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.queued.notify_one();
+        }
+    }
+}
+
+/// Counters of the recordings that did not go straight from the queue to the database
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    /// Recordings waiting in memory
+    pub queued: usize,
+
+    /// Recordings lost because the queue was full or they could not be written or spilled
+    pub dropped: u64,
+
+    /// Recordings in batches the database rejected, counted once per retry
+    pub retried: u64,
+
+    /// Recordings appended to the spill file
+    pub spilled: u64,
+
+    /// Recordings read back from the spill file and written to the database
+    pub replayed: u64,
+}
+
+/// The task that writes queued recordings to the database
+pub struct Writer {
+    handle: JoinHandle<()>,
+    shared: Arc<Shared>,
+}
+
+impl Writer {
+    /// Wait until every sender has been dropped and the queue has been drained
+    pub async fn join(self) -> Result<(), JoinError> {
+        self.handle.await
+    }
+
+    /// Wait up to `timeout` for every sender to be dropped, then stop accepting recordings
+    /// and write the ones already queued
+    pub async fn close(mut self, timeout: Duration) -> Result<(), JoinError> {
+        match tokio::time::timeout(timeout, &mut self.handle).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("Recordings are still being queued, closing the HAR queue");
+                self.shared.close();
+                self.handle.await
+            }
+        }
+    }
+}
+
+struct Shared {
+    recordings: Mutex<VecDeque<Recording>>,
+    capacity: usize,
+    overflow: Overflow,
+    max_retries: u32,
+
+    spill_file: PathBuf,
+    /// Serializes access to the spill file. Set while it or its replay file may hold recordings
+    spill_pending: tokio::sync::Mutex<bool>,
+
+    /// The number of live `HarQueue` clones
+    senders: AtomicUsize,
+    closed: AtomicBool,
+
+    /// Wakes the writer when a recording is queued, the last sender is dropped or the queue is
+    /// closed
+    queued: Notify,
+    /// Wakes senders waiting for room in the queue
+    dequeued: Notify,
+
+    dropped: AtomicU64,
+    retried: AtomicU64,
+    spilled: AtomicU64,
+    replayed: AtomicU64,
+}
+
+impl Shared {
+    /// Take the next batch of recordings, waiting for one to be queued
+    ///
+    /// Returns `None` once the queue is empty and either closed or without senders.
+    async fn next_batch(&self) -> Option<Vec<Recording>> {
+        loop {
+            {
+                let mut recordings = self.recordings.lock().unwrap();
+                if !recordings.is_empty() {
+                    let count = BATCH_SIZE.min(recordings.len());
+                    let batch = recordings.drain(..count).collect();
+                    self.dequeued.notify_waiters();
+                    return Some(batch);
+                }
+            }
+
+            if self.closed.load(Ordering::Acquire) || self.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+
+            self.queued.notified().await;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.recordings.lock().unwrap().is_empty()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.queued.notify_one();
+        self.dequeued.notify_waiters();
+    }
+
+    fn dropped(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Append recordings to the spill file, counting them as dropped if that fails
+    async fn spill(&self, recordings: &[Recording]) -> Result<()> {
+        let result = async {
+            let mut lines = Vec::new();
+            for recording in recordings {
+                serde_json::to_writer(&mut lines, recording)?;
+                lines.push(b'\n');
+            }
+
+            let mut pending = self.spill_pending.lock().await;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.spill_file)
+                .await?;
+            file.write_all(&lines).await?;
+            file.flush().await?;
+            *pending = true;
+
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                self.spilled
+                    .fetch_add(recordings.len() as u64, Ordering::Relaxed);
+            }
+            Err(_) => self.dropped(recordings.len()),
+        }
+
+        result
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            queued: self.recordings.lock().unwrap().len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            spilled: self.spilled.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Spawn the task that writes queued recordings to the database
+///
+/// Recordings left in the spill file are written first, again whenever the queue is empty and
+/// once more before the task finishes.
+pub async fn queue(db: SqlitePool, config: &config::Queue) -> (HarQueue, Writer) {
+    let shared = Arc::new(Shared {
+        recordings: Mutex::new(VecDeque::with_capacity(config.capacity)),
+        capacity: config.capacity.max(1),
+        overflow: config.overflow,
+        max_retries: config.max_retries,
+        spill_file: config.spill_file.clone(),
+        // A previous run may have left recordings behind
+        spill_pending: tokio::sync::Mutex::new(true),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        queued: Notify::new(),
+        dequeued: Notify::new(),
+        dropped: AtomicU64::new(0),
+        retried: AtomicU64::new(0),
+        spilled: AtomicU64::new(0),
+        replayed: AtomicU64::new(0),
+    });
+
+    let writer_shared = shared.clone();
+    let handle = tokio::spawn(async move {
+        let shared = writer_shared;
+
+        replay_spill(&db, &shared).await;
+        while let Some(batch) = shared.next_batch().await {
+            write(&db, &shared, batch).await;
+
+            if shared.is_empty() {
+                replay_spill(&db, &shared).await;
+            }
+        }
+        // Recordings may have been spilled after the last batch was written
+        replay_spill(&db, &shared).await;
+        tracing::debug!("har writer channel has been closed");
+
+        let stats = shared.stats();
+        if stats.dropped > 0 {
+            tracing::warn!(
+                "{} HARs were dropped, recordings are incomplete",
+                stats.dropped
+            );
+        }
+    });
+
+    (
+        HarQueue {
+            shared: shared.clone(),
+        },
+        Writer { handle, shared },
+    )
+}
+
+/// Write a batch to the database, spilling or dropping it if every attempt fails
+async fn write(db: &SqlitePool, shared: &Shared, batch: Vec<Recording>) {
+    let Err(e) = insert_with_retry(db, shared, &batch).await else {
+        return;
+    };
+
+    if shared.overflow == Overflow::Spill {
+        match shared.spill(&batch).await {
+            Ok(()) => tracing::error!(
+                "Error while saving HAR, spilled {} recordings: {}",
+                batch.len(),
+                e
+            ),
+            Err(spill_err) => tracing::error!(
+                "Error while saving HAR, dropped {} recordings: {}. Spilling failed: {}",
+                batch.len(),
+                e,
+                spill_err
+            ),
+        }
+    } else {
+        shared.dropped(batch.len());
+        tracing::error!(
+            "Error while saving HAR, dropped {} recordings: {}",
+            batch.len(),
+            e
+        );
+    }
+}
+
+/// Insert a batch, retrying up to `max_retries` times with exponential backoff
+async fn insert_with_retry(db: &SqlitePool, shared: &Shared, batch: &[Recording]) -> Result<()> {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retries = 0;
+
+    loop {
+        match db::insert_request(db, batch).await {
+            Ok(()) => return Ok(()),
+            Err(e) if retries < shared.max_retries => {
+                retries += 1;
+                shared
+                    .retried
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                tracing::warn!(
+                    "Error while saving HAR, retrying in {}ms: {}",
+                    backoff.as_millis(),
+                    e
+                );
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Write the recordings in the spill file to the database
+///
+/// The spill file is renamed to a replay file before it is read, so recordings spilled while it
+/// is written go to a new spill file instead of waiting for the database.
+async fn replay_spill(db: &SqlitePool, shared: &Shared) {
+    let path = replay_file(&shared.spill_file);
+
+    loop {
+        {
+            let mut pending = shared.spill_pending.lock().await;
+            if !*pending {
+                return;
+            }
+
+            // A replay file left by an earlier attempt holds older recordings, so it is written
+            // before the spill file takes its place
+            match tokio::fs::try_exists(&path).await {
+                Ok(true) => {}
+                Ok(false) => match tokio::fs::rename(&shared.spill_file, &path).await {
+                    Ok(()) => *pending = false,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        *pending = false;
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Error while renaming {}: {}",
+                            shared.spill_file.display(),
+                            e
+                        );
+                        return;
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Error while reading {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+
+        if !replay(db, shared, &path).await {
+            *shared.spill_pending.lock().await = true;
+            return;
+        }
+    }
+}
+
+/// The file the spill file is moved to while it is written to the database
+fn replay_file(spill_file: &std::path::Path) -> PathBuf {
+    let mut path = spill_file.as_os_str().to_owned();
+    path.push(".replay");
+    path.into()
+}
+
+/// Write the recordings in a replay file to the database and remove it, returning whether it is
+/// gone
+///
+/// If a batch cannot be written, the file is replaced with the recordings not yet written so
+/// they are tried again later.
+async fn replay(db: &SqlitePool, shared: &Shared, path: &std::path::Path) -> bool {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return true,
+        Err(e) => {
+            tracing::error!("Error while reading {}: {}", path.display(), e);
+            return false;
+        }
+    };
+
+    // Malformed lines are counted once and left out of any rewritten file
+    let (lines, recordings): (Vec<&[u8]>, Vec<Recording>) = bytes
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            serde_json::from_slice(line)
+                .inspect_err(|e| {
+                    tracing::error!("Skipping malformed line in {}: {}", path.display(), e);
+                    shared.dropped(1);
+                })
+                .ok()
+                .map(|recording| (line, recording))
+        })
+        .unzip();
+
+    let mut replayed = 0;
+    for (i, batch) in recordings.chunks(BATCH_SIZE).enumerate() {
+        if let Err(e) = insert_with_retry(db, shared, batch).await {
+            tracing::error!("Error while replaying {}: {}", path.display(), e);
+
+            let mut remaining = lines[i * BATCH_SIZE..].join(&b'\n');
+            remaining.push(b'\n');
+            let _ = rewrite(path, &remaining).await.inspect_err(|e| {
+                tracing::error!("Error while rewriting {}: {}", path.display(), e);
+            });
+            return false;
+        }
+
+        replayed += batch.len();
+        shared
+            .replayed
+            .fetch_add(batch.len() as u64, Ordering::Relaxed);
+    }
+    tracing::debug!("Replayed {} HARs from {}", replayed, path.display());
+
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!("Error while removing {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Replace the contents of a file without leaving it half written
+async fn rewrite(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
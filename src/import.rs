@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use sqlx::sqlite::SqlitePool;
+use uuid::Uuid;
+
+use crate::config;
+use crate::db;
+use crate::har::{Har, HarFile, Recording};
+
+/// Number of entries inserted per statement
+const BATCH_SIZE: usize = 100;
+
+/// Store every entry of the HAR file at `path` as its own request
+///
+/// Returns the ids the entries were stored under.
+pub async fn import(config: &config::Config, path: impl AsRef<Path>) -> Result<Vec<Uuid>> {
+    let db = db::init_db(&config.database).await?;
+    let bytes = tokio::fs::read(path).await?;
+
+    import_har(&db, &bytes).await
+}
+
+/// Store every entry of a HAR document as its own request
+///
+/// Accepts both the standard `{"log": ...}` document and the bare log park stores.
+pub async fn import_har(db: &SqlitePool, bytes: &[u8]) -> Result<Vec<Uuid>> {
+    store(db, parse(bytes)?).await
+}
+
+/// Store every entry of a parsed HAR document as its own request
+///
+/// Entries are inserted in batches within one transaction, so either every entry is stored or
+/// none is.
+pub async fn store(db: &SqlitePool, har: Har) -> Result<Vec<Uuid>> {
+    let mut recordings = Recording::split(har);
+    let request_ids = recordings.iter().map(|r| r.request_id).collect();
+
+    let mut tx = db.begin().await?;
+    while !recordings.is_empty() {
+        let batch: Vec<Recording> = recordings
+            .drain(..BATCH_SIZE.min(recordings.len()))
+            .collect();
+        db::insert_requests_with(&mut tx, &batch).await?;
+    }
+    tx.commit().await?;
+
+    Ok(request_ids)
+}
+
+/// Parse a HAR document in either the standard or the bare form
+pub fn parse(bytes: &[u8]) -> Result<Har> {
+    match serde_json::from_slice::<HarFile>(bytes) {
+        Ok(file) => Ok(file.log),
+        Err(file_err) => match serde_json::from_slice::<Har>(bytes) {
+            Ok(har) => Ok(har),
+            Err(_) => Err(anyhow!("Malformed har file: {}", file_err)),
+        },
+    }
+}
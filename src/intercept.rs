@@ -0,0 +1,414 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, TimeDelta, Utc};
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::upgrade::Upgraded;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SerialNumber,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{CertifiedKey, SigningKey};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use url::Url;
+use uuid::Uuid;
+
+use crate::config;
+use crate::har;
+use crate::proxy::ConnectionInfo;
+use crate::AppState;
+
+/// Marks a request that was decrypted from an intercepted CONNECT tunnel
+///
+/// Such requests are sent to the target of the tunnel instead of the configured upstream server.
+#[derive(Clone, Debug)]
+pub struct Intercepted {
+    /// `https://` followed by the authority of the CONNECT request
+    pub origin: Url,
+}
+
+/// Most leaf certificates kept for reuse. The oldest is dropped to make room for a new one
+const MAX_LEAVES: usize = 1000;
+
+/// A root certificate that signs a certificate for every host park intercepts traffic to
+pub struct CertificateAuthority {
+    cert: Certificate,
+    key: KeyPair,
+
+    /// Every leaf certificate uses the same key so minting one only needs a signature
+    leaf_key: KeyPair,
+    signing_key: Arc<dyn SigningKey>,
+
+    /// Leaf certificates by host name
+    leaves: Mutex<Leaves>,
+}
+
+/// Leaf certificates by host name, along with the order they were minted in
+#[derive(Default)]
+struct Leaves {
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+    minted: VecDeque<String>,
+}
+
+impl CertificateAuthority {
+    /// Load the root certificate and key, generating and saving new ones if either file is missing
+    pub fn load_or_generate(config: &config::Intercept) -> Result<Self> {
+        let (cert, key) = if config.ca_cert.exists() && config.ca_key.exists() {
+            let key = KeyPair::from_pem(&std::fs::read_to_string(&config.ca_key)?)?;
+            let params =
+                CertificateParams::from_ca_cert_pem(&std::fs::read_to_string(&config.ca_cert)?)?;
+
+            // Signing the parameters again gives a certificate with the same subject and key as
+            // the one clients trust, which is all leaf certificates are checked against
+            (params.self_signed(&key)?, key)
+        } else {
+            let key = KeyPair::generate()?;
+
+            let mut params = CertificateParams::default();
+            params.distinguished_name = DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "park proxy CA");
+            params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+            params.key_usages = vec![
+                KeyUsagePurpose::KeyCertSign,
+                KeyUsagePurpose::CrlSign,
+                KeyUsagePurpose::DigitalSignature,
+            ];
+            set_validity(&mut params, 10 * 365);
+            let cert = params.self_signed(&key)?;
+
+            std::fs::write(&config.ca_cert, cert.pem())?;
+            write_private(&config.ca_key, &key.serialize_pem())?;
+            tracing::info!(
+                "Generated root certificate {}. Clients must trust it for intercepted requests",
+                config.ca_cert.display()
+            );
+
+            (cert, key)
+        };
+
+        let leaf_key = KeyPair::generate()?;
+        let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            leaf_key.serialize_der(),
+        )))?;
+
+        Ok(CertificateAuthority {
+            cert,
+            key,
+            leaf_key,
+            signing_key,
+            leaves: Mutex::new(Leaves::default()),
+        })
+    }
+
+    /// The certificate chain and key to present for `host`, minted on first use
+    fn certified_key(&self, host: &str) -> Result<Arc<CertifiedKey>> {
+        if let Some(certified_key) = self.leaves.lock().unwrap().by_host.get(host) {
+            return Ok(certified_key.clone());
+        }
+
+        // IP addresses are added as IP address subject alternative names
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, host);
+        // Clients reject certificates from the same issuer that share a serial number
+        params.serial_number = Some(SerialNumber::from_slice(Uuid::now_v7().as_bytes()));
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        set_validity(&mut params, 365);
+        let cert = params.signed_by(&self.leaf_key, &self.cert, &self.key)?;
+
+        let certified_key = Arc::new(CertifiedKey::new(
+            vec![cert.der().clone(), self.cert.der().clone()],
+            self.signing_key.clone(),
+        ));
+
+        let mut leaves = self.leaves.lock().unwrap();
+        if leaves
+            .by_host
+            .insert(host.to_string(), certified_key.clone())
+            .is_none()
+        {
+            leaves.minted.push_back(host.to_string());
+            if leaves.minted.len() > MAX_LEAVES {
+                if let Some(oldest) = leaves.minted.pop_front() {
+                    leaves.by_host.remove(&oldest);
+                }
+            }
+        }
+
+        Ok(certified_key)
+    }
+}
+
+/// Presents the leaf certificate for the host of the CONNECT request
+///
+/// Decrypted requests are sent to that host whatever the client asks for in SNI, so no
+/// certificate is minted for any other name.
+struct Resolver {
+    ca: Arc<CertificateAuthority>,
+    host: String,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            if !server_name.eq_ignore_ascii_case(&self.host) {
+                tracing::debug!(
+                    "Client asked for {} in a tunnel to {}",
+                    server_name,
+                    self.host
+                );
+            }
+        }
+
+        self.ca
+            .certified_key(&self.host)
+            .inspect_err(|e| {
+                tracing::error!("Error while minting certificate for {}: {}", self.host, e)
+            })
+            .ok()
+    }
+}
+
+/// Terminate TLS on the upgraded connection of a CONNECT request to `addr` and proxy every
+/// request sent through it
+///
+/// Returns what went through the tunnel once the client closes it, it fails or no data is sent
+/// for `idle_timeout`. The future is boxed because `proxy` both spawns it and handles the
+/// requests it receives, which would otherwise make the type of the future recursive.
+pub fn intercept(
+    config: Arc<config::Config>,
+    state: AppState,
+    ca: Arc<CertificateAuthority>,
+    upgraded: Upgraded,
+    addr: String,
+    connection: Option<ConnectionInfo>,
+    idle_timeout: Duration,
+) -> Pin<Box<dyn Future<Output = har::Tunnel> + Send>> {
+    Box::pin(async move {
+        let traffic = Arc::new(Traffic::new());
+        let client = Metered {
+            inner: TokioIo::new(upgraded),
+            traffic: traffic.clone(),
+        };
+
+        // Dropping the connection closes the tunnel
+        let close_reason = tokio::select! {
+            result = serve(config, state, ca, client, addr, connection) => match result {
+                Ok(()) => "client closed".to_string(),
+                Err(e) => format!("client error: {}", e),
+            },
+            _ = traffic.idle(idle_timeout) => "idle timeout".to_string(),
+        };
+
+        har::Tunnel {
+            bytes_sent: traffic.sent.load(Ordering::Relaxed),
+            bytes_received: traffic.received.load(Ordering::Relaxed),
+            close_reason,
+        }
+    })
+}
+
+async fn serve(
+    config: Arc<config::Config>,
+    state: AppState,
+    ca: Arc<CertificateAuthority>,
+    client: Metered<TokioIo<Upgraded>>,
+    addr: String,
+    connection: Option<ConnectionInfo>,
+) -> Result<()> {
+    let authority = addr.parse::<http::uri::Authority>()?;
+    let origin = Url::parse(&format!("https://{}", authority))?;
+
+    let mut server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(Resolver {
+            ca,
+            host: authority.host().to_string(),
+        }));
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let stream = TlsAcceptor::from(Arc::new(server_config))
+        .accept(client)
+        .await?;
+
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        let config = config.clone();
+        let state = state.clone();
+
+        // Record the URL the client requested rather than the path alone
+        if req.uri().authority().is_none() {
+            let mut parts = req.uri().clone().into_parts();
+            parts.scheme = Some(http::uri::Scheme::HTTPS);
+            parts.authority = Some(authority.clone());
+            if let Ok(uri) = http::Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+        }
+        req.extensions_mut().insert(Intercepted {
+            origin: origin.clone(),
+        });
+        if let Some(connection) = connection {
+            req.extensions_mut().insert(ConnectionInfo {
+                tls: true,
+                ..connection
+            });
+        }
+
+        async move {
+            if config.mock.enabled {
+                crate::mock(config, state, req).await
+            } else {
+                crate::proxy(config, state, req).await
+            }
+        }
+    });
+
+    hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+        .map_err(|e| anyhow!(e))
+}
+
+/// Bytes that went through an intercepted tunnel and when data last went either way
+struct Traffic {
+    /// Bytes read from the client
+    sent: AtomicU64,
+
+    /// Bytes written to the client
+    received: AtomicU64,
+
+    last_active: Mutex<Instant>,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Traffic {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn record(&self, counter: &AtomicU64, bytes: usize) {
+        if bytes > 0 {
+            counter.fetch_add(bytes as u64, Ordering::Relaxed);
+            *self.last_active.lock().unwrap() = Instant::now();
+        }
+    }
+
+    /// Wait until no data has gone either way for `timeout`
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = *self.last_active.lock().unwrap() + timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// The client side of an intercepted tunnel, counting the bytes read from and written to it
+struct Metered<T> {
+    inner: T,
+    traffic: Arc<Traffic>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.traffic
+            .record(&self.traffic.sent, buf.filled().len() - filled);
+
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.traffic.record(&self.traffic.received, written);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Make a certificate valid from a day ago for `days` days
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let now = Utc::now();
+    let not_before = now - TimeDelta::days(1);
+    let not_after = now + TimeDelta::days(days);
+
+    params.not_before = rcgen::date_time_ymd(
+        not_before.year(),
+        not_before.month() as u8,
+        not_before.day() as u8,
+    );
+    params.not_after = rcgen::date_time_ymd(
+        not_after.year(),
+        not_after.month() as u8,
+        not_after.day() as u8,
+    );
+}
+
+/// Write a file only the current user can read
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents.as_bytes())?;
+
+    Ok(())
+}
@@ -1,34 +1,87 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 
 mod api;
 mod config;
 mod db;
+mod export;
 mod har;
+mod import;
+mod intercept;
+mod migrate;
+mod mock;
 mod proxy;
+mod replay;
+mod tunnel;
+mod websocket;
 
 pub use api::api;
 pub use config::Config;
-pub use proxy::proxy;
+pub use export::export;
+pub use har::writer::{HarQueue, QueueStats, Writer};
+pub use har::Recording;
+pub use import::import;
+pub use migrate::{
+    compress_bodies, enable_incremental_vacuum, migrate, schema_status, Migration, MigrationState,
+};
+pub use mock::mock;
+pub use proxy::{proxy, ConnectionInfo};
+pub use replay::{replay, ReplayOptions, Report};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: sqlx::SqlitePool,
     pub client: reqwest::Client,
-    pub har_queue: tokio::sync::mpsc::Sender<crate::har::Har>,
+    pub har_queue: crate::har::writer::HarQueue,
+
+    /// Recorded entries read from `mock.har_file`
+    pub mock_entries: Option<Arc<Vec<::har::v1_3::Entries>>>,
+
+    /// Signs certificates for intercepted CONNECT tunnels when `intercept.enabled` is set
+    pub certificate_authority: Option<Arc<crate::intercept::CertificateAuthority>>,
 }
 
 pub async fn app(config: &config::Config) -> Result<AppState> {
+    let (state, _writer) = app_with_writer(config).await?;
+
+    Ok(state)
+}
+
+/// Build the application state along with the task that writes queued HARs to the database
+///
+/// The writer task finishes once every clone of the state has been dropped, or the writer has
+/// been closed, and all queued HARs have been written.
+pub async fn app_with_writer(config: &config::Config) -> Result<(AppState, Writer)> {
     let client = reqwest::ClientBuilder::new()
         .timeout(std::time::Duration::from_secs(config.server.server_timeout))
         .build()?;
     let db = crate::db::init_db(&config.database).await?;
-    let har_queue = crate::har::writer::queue(db.clone()).await;
+    let (har_queue, writer) = crate::har::writer::queue(db.clone(), &config.queue).await;
+
+    let mock_entries = match &config.mock.har_file {
+        Some(har_file) => {
+            let bytes = tokio::fs::read(har_file).await?;
+            Some(Arc::new(crate::import::parse(&bytes)?.into_log().entries))
+        }
+        None => None,
+    };
+
+    let certificate_authority = if config.intercept.enabled {
+        Some(Arc::new(
+            crate::intercept::CertificateAuthority::load_or_generate(&config.intercept)?,
+        ))
+    } else {
+        None
+    };
 
     let state = crate::AppState {
         db,
         client,
         har_queue,
+        mock_entries,
+        certificate_authority,
     };
 
-    Ok(state)
+    Ok((state, writer))
 }
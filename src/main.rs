@@ -1,14 +1,16 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{Arg, Command};
-use futures_util::future::join;
+use clap::{Arg, ArgAction, Command};
+use futures_util::future::join3;
 use hyper::body::Incoming;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::Request;
 use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::graceful::GracefulShutdown;
 use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -33,8 +35,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .value_name("FILE")
                 .conflicts_with("address"),
         )
+        .subcommand(
+            Command::new("export")
+                .about("Export recorded requests as a single HAR file")
+                .arg(config_arg())
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .help("Only export requests matching the filter. Uses the same query string format as the GET /requests API. Example: status=5xx&path=/checkout")
+                        .value_name("QUERY"),
+                )
+                .arg(
+                    Arg::new("pages")
+                        .long("pages")
+                        .help("Group entries into one page per host")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .help("Write the HAR file to FILE instead of stdout")
+                        .value_name("FILE"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Import every entry of a HAR file as a recorded request")
+                .arg(config_arg())
+                .arg(
+                    Arg::new("file")
+                        .help("The HAR file to import")
+                        .value_name("FILE")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Replay recorded requests against the upstream server and report which responses changed")
+                .arg(config_arg())
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .help("Only replay requests matching the filter. Uses the same query string format as the GET /requests API. Example: since=2024-09-01T00:00:00Z&path=/checkout")
+                        .value_name("QUERY"),
+                )
+                .arg(
+                    Arg::new("header")
+                        .long("header")
+                        .help("A response header to compare. May be repeated")
+                        .value_name("NAME")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("ignore")
+                        .long("ignore")
+                        .help("A JSON field name or path such as $.meta.generated_at or $.items[*].id to skip when comparing bodies. May be repeated")
+                        .value_name("FIELD")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("db")
+                .about("Inspect and migrate the database schema")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("status")
+                        .about("List every migration and whether it has been applied")
+                        .arg(config_arg()),
+                )
+                .subcommand(
+                    Command::new("migrate")
+                        .about("Apply pending migrations, compress bodies stored inline by earlier versions and convert the database to incremental vacuum")
+                        .arg(config_arg())
+                        .arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .help("List the changes that would be made without making them")
+                                .action(ArgAction::SetTrue),
+                        ),
+                ),
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("export", matches)) => return commands::export(matches).await,
+        Some(("import", matches)) => return commands::import(matches).await,
+        Some(("replay", matches)) => return commands::replay(matches).await,
+        Some(("db", matches)) => return commands::db(matches).await,
+        _ => {}
+    }
+
     let config: park::Config = if let Some(address) = matches.get_one::<String>("address") {
         let address = if let Ok(socket) = address.parse::<SocketAddr>() {
             url::Url::parse(&format!("http://{}", socket))?
@@ -78,14 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             }
         }
     } else if let Some(config_file) = matches.get_one::<String>("config") {
-        let content = std::fs::read_to_string(config_file)?;
-        match toml::from_str(&content) {
-            Ok(config) => config,
-            Err(err) => {
-                eprintln!("Error in configuration: {}", err);
-                std::process::exit(1);
-            }
-        }
+        read_config(config_file)?
     } else {
         eprintln!("You must specify either a domain or a configuration file.");
         std::process::exit(1);
@@ -99,23 +184,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let state = park::app(&config).await?;
+    match (&config.server.address, &config.server.destinations.allowed_hosts) {
+        (None, None) => tracing::warn!(
+            "server.destinations.allowed_hosts is not set so every forwarded request and CONNECT tunnel is refused"
+        ),
+        (Some(_), None) => tracing::warn!(
+            "server.destinations.allowed_hosts is not set so CONNECT tunnels may be opened to any host, including loopback, on {}",
+            if config.server.destinations.allowed_ports.is_some() {
+                "the allowed ports"
+            } else {
+                "any port"
+            }
+        ),
+        _ => {}
+    }
+
+    let (state, writer) = park::app_with_writer(&config).await?;
     let config = Arc::new(config);
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout);
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
 
     let proxy_config = config.clone();
     let proxy_state = state.clone();
+    let mut proxy_shutdown = shutdown_rx.clone();
     let proxy_srv = async move {
-        let tls_acceptor =
-            if proxy_config.server.ssl_cert.is_some() && proxy_config.server.ssl_key.is_some() {
-                let rustls_config = tls::rustls_server_config(
-                    proxy_config.server.ssl_key.as_ref().unwrap(),
-                    proxy_config.server.ssl_cert.as_ref().unwrap(),
-                );
-
-                Some(tokio_rustls::TlsAcceptor::from(rustls_config))
-            } else {
-                None
-            };
+        let tls_acceptor = if let (Some(ssl_cert), Some(ssl_key)) =
+            (&proxy_config.server.ssl_cert, &proxy_config.server.ssl_key)
+        {
+            let rustls_config = tls::rustls_server_config(ssl_key, ssl_cert);
+
+            Some(tokio_rustls::TlsAcceptor::from(rustls_config))
+        } else {
+            None
+        };
 
         let listener = TcpListener::bind(proxy_config.server.bind)
             .await
@@ -126,16 +227,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .local_addr()
                 .expect("Proxy failed to get local address")
         );
+        let graceful = GracefulShutdown::new();
         loop {
-            let (cnx, addr) = listener
-                .accept()
-                .await
-                .expect("Proxy failed to accept connection");
+            let (cnx, addr) = tokio::select! {
+                accepted = listener.accept() => accepted.expect("Proxy failed to accept connection"),
+                _ = proxy_shutdown.changed() => break,
+            };
 
             let config = proxy_config.clone();
             let state = proxy_state.clone();
-            let service = service_fn(move |req: Request<Incoming>| {
-                park::proxy(config.clone(), state.clone(), req)
+            let connection = park::ConnectionInfo {
+                remote_addr: addr,
+                tls: tls_acceptor.is_some(),
+            };
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                let config = config.clone();
+                let state = state.clone();
+                req.extensions_mut().insert(connection);
+                async move {
+                    if config.mock.enabled {
+                        park::mock(config, state, req).await
+                    } else {
+                        park::proxy(config, state, req).await
+                    }
+                }
             });
 
             let mut server = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
@@ -158,27 +273,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                         })
                         .expect("TLS failed to accept connection");
 
+                    let io = TokioIo::new(stream);
+                    let conn = server.serve_connection_with_upgrades(io, service);
+                    let conn = graceful.watch(conn.into_owned());
                     tokio::task::spawn(async move {
-                        let io = TokioIo::new(stream);
-                        if let Err(err) = server.serve_connection_with_upgrades(io, service).await {
+                        if let Err(err) = conn.await {
                             tracing::error!("Error serving TLS proxy: {:?}", err);
                         }
                     });
                 }
                 None => {
+                    let io = TokioIo::new(cnx);
+                    let conn = server.serve_connection_with_upgrades(io, service);
+                    let conn = graceful.watch(conn.into_owned());
                     tokio::task::spawn(async move {
-                        let io = TokioIo::new(cnx);
-                        if let Err(err) = server.serve_connection_with_upgrades(io, service).await {
+                        if let Err(err) = conn.await {
                             tracing::error!("Error serving proxy: {:?}", err);
                         }
                     });
                 }
             }
         }
+
+        drop(listener);
+        drain("Proxy", graceful, shutdown_timeout).await;
     };
 
     let api_config = config.clone();
     let api_state = state.clone();
+    let mut api_shutdown = shutdown_rx;
     let api_srv = async move {
         let listener = TcpListener::bind("127.0.0.1:9000")
             .await
@@ -189,36 +312,273 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .local_addr()
                 .expect("API failed to get local address")
         );
+        let graceful = GracefulShutdown::new();
         loop {
-            let (stream, _) = listener
-                .accept()
-                .await
-                .expect("API failed to accept connection");
+            let (stream, _) = tokio::select! {
+                accepted = listener.accept() => accepted.expect("API failed to accept connection"),
+                _ = api_shutdown.changed() => break,
+            };
 
             let io = TokioIo::new(stream);
 
             let config = api_config.clone();
             let state = api_state.clone();
+            // hyper-util can only watch the upgradeable connections of the auto builder
+            let server =
+                hyper_util::server::conn::auto::Builder::new(TokioExecutor::new()).http1_only();
+            let conn = server.serve_connection_with_upgrades(
+                io,
+                service_fn(move |req| park::api(config.clone(), state.clone(), req)),
+            );
+            let conn = graceful.watch(conn.into_owned());
             tokio::task::spawn(async move {
-                if let Err(err) = http1::Builder::new()
-                    .serve_connection(
-                        io,
-                        service_fn(move |req| park::api(config.clone(), state.clone(), req)),
-                    )
-                    .with_upgrades()
-                    .await
-                {
+                if let Err(err) = conn.await {
                     tracing::error!("Error serving API: {:?}", err);
                 }
             });
         }
+
+        drop(listener);
+        drain("API", graceful, shutdown_timeout).await;
+    };
+
+    let signal = async move {
+        shutdown_signal().await;
+        tracing::info!("Shutting down");
+        let _ = shutdown_tx.send(());
     };
 
-    let _ret = join(proxy_srv, api_srv).await;
+    join3(proxy_srv, api_srv, signal).await;
+
+    // HARs are queued by tasks that outlive the connections, so the writer waits for them before
+    // closing the queue
+    drop(state);
+    writer.close(shutdown_timeout).await?;
+    tracing::info!("Every queued HAR has been written");
 
     Ok(())
 }
 
+/// Resolves when the process receives SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Wait for the connections of a server that stopped accepting new ones to finish, giving up
+/// after `timeout`
+async fn drain(name: &str, graceful: GracefulShutdown, timeout: Duration) {
+    if tokio::time::timeout(timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "{} connections still open after {} seconds, closing them",
+            name,
+            timeout.as_secs()
+        );
+    }
+}
+
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .short('c')
+        .long("config")
+        .help("Path to the configuration file")
+        .value_name("FILE")
+        .required(true)
+}
+
+fn read_config(
+    config_file: &str,
+) -> Result<park::Config, Box<dyn std::error::Error + Send + Sync>> {
+    let content = std::fs::read_to_string(config_file)?;
+    match toml::from_str(&content) {
+        Ok(config) => Ok(config),
+        Err(err) => {
+            eprintln!("Error in configuration: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Logs are written to stderr so they do not mix with command output written to stdout
+fn init_command_tracing() {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "park=warn".into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+}
+
+mod commands {
+    use clap::ArgMatches;
+
+    type Result = std::result::Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    pub async fn export(matches: &ArgMatches) -> Result {
+        super::init_command_tracing();
+
+        let config = super::read_config(matches.get_one::<String>("config").unwrap())?;
+        let filter = matches
+            .get_one::<String>("filter")
+            .map(String::as_str)
+            .unwrap_or_default();
+        let pages = matches.get_flag("pages");
+
+        match matches.get_one::<String>("output") {
+            Some(output) => {
+                let file = tokio::fs::File::create(output).await?;
+                park::export(&config, filter, pages, tokio::io::BufWriter::new(file)).await?;
+            }
+            None => park::export(&config, filter, pages, tokio::io::stdout()).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn import(matches: &ArgMatches) -> Result {
+        super::init_command_tracing();
+
+        let config = super::read_config(matches.get_one::<String>("config").unwrap())?;
+        let file = matches.get_one::<String>("file").unwrap();
+
+        let request_ids = park::import(&config, file).await?;
+        println!("Imported {} requests from {}", request_ids.len(), file);
+
+        Ok(())
+    }
+
+    /// Prints the report as JSON and exits with a non-zero status if any response changed or a
+    /// request could not be replayed
+    pub async fn replay(matches: &ArgMatches) -> Result {
+        super::init_command_tracing();
+
+        let config = super::read_config(matches.get_one::<String>("config").unwrap())?;
+        let values = |id: &str| {
+            matches
+                .get_many::<String>(id)
+                .map(|values| values.cloned().collect())
+                .unwrap_or_default()
+        };
+        let options = park::ReplayOptions {
+            filter: matches
+                .get_one::<String>("filter")
+                .cloned()
+                .unwrap_or_default(),
+            headers: values("header"),
+            ignore: values("ignore"),
+        };
+
+        let report = park::replay(config, options).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+
+        if report.changed > 0 || report.failed > 0 {
+            std::process::exit(1);
+        }
+
+        Ok(())
+    }
+
+    /// `status` lists every migration known to park or applied to the database. `migrate`
+    /// applies the pending ones, compresses bodies stored inline by earlier versions and converts
+    /// the database to incremental vacuum
+    pub async fn db(matches: &ArgMatches) -> Result {
+        super::init_command_tracing();
+
+        match matches.subcommand() {
+            Some(("status", matches)) => {
+                let config = super::read_config(matches.get_one::<String>("config").unwrap())?;
+
+                for migration in park::schema_status(&config).await? {
+                    let state = match migration.state {
+                        park::MigrationState::Applied(installed_on) => {
+                            format!("applied {}", installed_on)
+                        }
+                        park::MigrationState::Pending => "pending".to_string(),
+                        park::MigrationState::Unknown => {
+                            "unknown, applied by a newer park".to_string()
+                        }
+                    };
+                    println!(
+                        "{:<16}{:<32}{}",
+                        migration.version, migration.description, state
+                    );
+                }
+            }
+            Some(("migrate", matches)) => {
+                let config = super::read_config(matches.get_one::<String>("config").unwrap())?;
+                let dry_run = matches.get_flag("dry-run");
+
+                let migrations = park::migrate(&config, dry_run).await?;
+                for migration in &migrations {
+                    println!(
+                        "{} {} {}",
+                        if dry_run { "Would apply" } else { "Applied" },
+                        migration.version,
+                        migration.description
+                    );
+                }
+
+                let compressed = park::compress_bodies(&config, dry_run).await?;
+                if compressed > 0 {
+                    println!(
+                        "{} the bodies of {} requests stored by an earlier version",
+                        if dry_run {
+                            "Would compress"
+                        } else {
+                            "Compressed"
+                        },
+                        compressed
+                    );
+                }
+
+                // Done last as it rewrites the whole file, which may take a while, and reclaims
+                // the space freed by compressing bodies
+                let converted = park::enable_incremental_vacuum(&config, dry_run).await?;
+                if let Some(size) = converted {
+                    println!(
+                        "{} the {} MiB database to incremental vacuum",
+                        if dry_run {
+                            "Would convert"
+                        } else {
+                            "Converted"
+                        },
+                        size / (1024 * 1024)
+                    );
+                }
+
+                if migrations.is_empty() && compressed == 0 && converted.is_none() {
+                    println!("Database is up to date");
+                }
+            }
+            _ => unreachable!("clap requires a db subcommand"),
+        }
+
+        Ok(())
+    }
+}
+
 mod tls {
     use rustls_pemfile::{certs, pkcs8_private_keys};
     use std::{fs::File, io::BufReader, path::Path, sync::Arc};
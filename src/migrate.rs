@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::SqlitePool;
+
+use crate::config;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// A migration known to this binary or applied to the database
+#[derive(Debug, Serialize)]
+pub struct Migration {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    /// Applied at the given time
+    Applied(String),
+
+    /// Not applied yet
+    Pending,
+
+    /// Applied to the database but unknown to this binary, which means a newer park created it
+    Unknown,
+}
+
+/// Every migration along with whether it has been applied, ordered by version
+pub async fn schema_status(config: &config::Config) -> Result<Vec<Migration>> {
+    let pool = crate::db::connect(&config.database.uri).await?;
+
+    status(&pool).await
+}
+
+/// Apply every pending migration, or only list them when `dry_run` is set
+///
+/// Returns the migrations that were, or would be, applied.
+pub async fn migrate(config: &config::Config, dry_run: bool) -> Result<Vec<Migration>> {
+    let pool = crate::db::connect(&config.database.uri).await?;
+
+    let pending: Vec<Migration> = status(&pool)
+        .await?
+        .into_iter()
+        .filter(|migration| migration.state == MigrationState::Pending)
+        .collect();
+
+    if !dry_run {
+        run(&pool).await?;
+    }
+
+    Ok(pending)
+}
+
+/// Compress the bodies of requests stored by earlier versions of park, or only count them when
+/// `dry_run` is set
+///
+/// Returns the number of requests whose bodies were, or would be, compressed.
+pub async fn compress_bodies(config: &config::Config, dry_run: bool) -> Result<u64> {
+    let pool = crate::db::connect(&config.database.uri).await?;
+
+    if !dry_run {
+        return crate::db::compress_inline_bodies(&pool).await;
+    }
+
+    // Requests are only marked once the migration adding the column has been applied
+    let marked: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info('requests') WHERE name = 'inline_bodies')",
+    )
+    .fetch_one(&pool)
+    .await?;
+    if !marked {
+        return Ok(0);
+    }
+
+    Ok(crate::db::inline_body_count(&pool).await? as u64)
+}
+
+/// Convert the database to incremental vacuum when it does not use it yet, or only check whether
+/// it would be converted when `dry_run` is set
+///
+/// Returns the size in bytes of the database that was, or would be, converted.
+pub async fn enable_incremental_vacuum(
+    config: &config::Config,
+    dry_run: bool,
+) -> Result<Option<i64>> {
+    let pool = crate::db::connect(&config.database.uri).await?;
+
+    let size = crate::db::without_incremental_vacuum(&pool).await?;
+    if size.is_some() && !dry_run {
+        crate::db::enable_incremental_vacuum(&pool).await?;
+    }
+
+    Ok(size)
+}
+
+/// Apply every pending migration, refusing to touch a database created by a newer park
+pub async fn run(pool: &SqlitePool) -> Result<()> {
+    if let Some(migration) = status(pool)
+        .await?
+        .into_iter()
+        .find(|migration| migration.state == MigrationState::Unknown)
+    {
+        return Err(anyhow!(
+            "Database has migration {} ({}) which this version of park does not know. Upgrade park to use this database",
+            migration.version,
+            migration.description
+        ));
+    }
+
+    MIGRATOR.run(pool).await?;
+
+    Ok(())
+}
+
+async fn status(pool: &SqlitePool) -> Result<Vec<Migration>> {
+    let mut applied = applied(pool).await?;
+
+    let mut migrations: Vec<Migration> = MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| Migration {
+            version: migration.version,
+            description: migration.description.to_string(),
+            state: match applied.remove(&migration.version) {
+                Some((_, installed_on)) => MigrationState::Applied(installed_on),
+                None => MigrationState::Pending,
+            },
+        })
+        .collect();
+
+    migrations.extend(
+        applied
+            .into_iter()
+            .map(|(version, (description, _))| Migration {
+                version,
+                description,
+                state: MigrationState::Unknown,
+            }),
+    );
+    migrations.sort_by_key(|migration| migration.version);
+
+    Ok(migrations)
+}
+
+/// The description and install time of every migration applied to the database by version
+async fn applied(pool: &SqlitePool) -> Result<HashMap<i64, (String, String)>> {
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !exists {
+        return Ok(HashMap::new());
+    }
+
+    let rows: Vec<(i64, String, String)> = sqlx::query_as(
+        "SELECT version, description, installed_on FROM _sqlx_migrations WHERE success = TRUE",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(version, description, installed_on)| (version, (description, installed_on)))
+        .collect())
+}
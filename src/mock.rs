@@ -0,0 +1,202 @@
+use ::har::v1_3::{Entries, Response as HarResponse};
+use anyhow::Result;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Bytes};
+use hyper::{Request, Response, StatusCode};
+use std::sync::Arc;
+
+use crate::config;
+use crate::db;
+use crate::har;
+use crate::proxy::proxy;
+use crate::AppState;
+
+/// Response header containing the id of the recording a mocked response was read from
+pub const MOCKED_FROM_HEADER: &str = "x-park-mocked-from";
+
+/// Maximum number of recordings with a matching method and path that are compared against a
+/// request. Their HARs are only loaded once the query string matches, newest first, until one
+/// matches
+const MAX_CANDIDATES: i64 = 1000;
+
+/// Answer a request with the most recent matching recorded response
+///
+/// Recordings are read from `mock.har_file` if set, otherwise from the database. Requests
+/// without a match are proxied upstream if `mock.fallthrough` is set.
+pub async fn mock<B>(
+    config: Arc<config::Config>,
+    state: AppState,
+    req: Request<B>,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>, anyhow::Error>
+where
+    B: Body + std::fmt::Debug,
+    B::Error: Into<anyhow::Error>,
+{
+    tracing::trace!("{:?}", req);
+
+    let (head, body) = req.into_parts();
+    let body = body.collect().await.map_err(Into::into)?.to_bytes();
+
+    let matcher = Matcher {
+        config: &config.mock,
+        req: &head,
+        body: &body,
+    };
+
+    let found = match state.mock_entries.as_ref() {
+        Some(entries) => entries
+            .iter()
+            .rev()
+            .find(|entry| matcher.matches(entry))
+            .map(|entry| (None, entry.response.clone())),
+        None => {
+            let method = config.mock.match_method.then(|| head.method.as_str());
+            let path = config.mock.match_path.then(|| head.uri.path());
+            let query = query_pairs(head.uri.query());
+
+            let mut found = None;
+            for (request_id, recorded_query) in
+                db::find_requests(&state.db, method, path, MAX_CANDIDATES).await?
+            {
+                if config.mock.match_query && query_pairs(recorded_query.as_deref()) != query {
+                    continue;
+                }
+
+                let Some(har) = db::get_request(&state.db, request_id).await? else {
+                    continue;
+                };
+                found = har
+                    .into_log()
+                    .entries
+                    .into_iter()
+                    .find(|entry| matcher.matches(entry))
+                    .map(|entry| (Some(request_id), entry.response));
+                if found.is_some() {
+                    break;
+                }
+            }
+            found
+        }
+    };
+
+    match found {
+        Some((request_id, response)) => {
+            let mut resp = recorded_response(response)?;
+            if let Some(request_id) = request_id {
+                resp.headers_mut()
+                    .insert(MOCKED_FROM_HEADER, request_id.to_string().parse()?);
+            }
+            Ok(resp)
+        }
+        None if config.mock.fallthrough => {
+            let req = Request::from_parts(head, Full::new(body));
+            proxy(config, state, req).await
+        }
+        None => {
+            tracing::debug!("No recorded response for {} {}", head.method, head.uri);
+            let body = Full::new(Bytes::from_static(
+                b"No recorded response matches the request",
+            ))
+            .map_err(anyhow::Error::from);
+            Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(BoxBody::new(body))
+                .unwrap())
+        }
+    }
+}
+
+/// Compares an incoming request with recorded entries using the criteria enabled in the config
+struct Matcher<'a> {
+    config: &'a config::Mock,
+    req: &'a http::request::Parts,
+    body: &'a Bytes,
+}
+
+impl Matcher<'_> {
+    fn matches(&self, entry: &Entries) -> bool {
+        // Browsers record requests that never got a response with status 0, which cannot be
+        // replayed
+        if u16::try_from(entry.response.status)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .is_none()
+        {
+            return false;
+        }
+
+        let recorded = &entry.request;
+        // Recorded URLs are absolute when imported from other tools
+        let uri = match recorded.url.parse::<http::Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return false,
+        };
+
+        if self.config.match_method
+            && !recorded
+                .method
+                .eq_ignore_ascii_case(self.req.method.as_str())
+        {
+            return false;
+        }
+
+        if self.config.match_path && uri.path() != self.req.uri.path() {
+            return false;
+        }
+
+        if self.config.match_query && query_pairs(uri.query()) != query_pairs(self.req.uri.query())
+        {
+            return false;
+        }
+
+        if self.config.match_body {
+            let recorded_body = recorded
+                .post_data
+                .as_ref()
+                .map(|post_data| {
+                    har::decode_text(post_data.text.clone(), post_data.encoding.as_deref())
+                })
+                .unwrap_or_else(|| Ok(Bytes::new()));
+
+            match recorded_body {
+                Ok(recorded_body) if recorded_body == self.body => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+/// Decoded query parameters sorted so they can be compared regardless of order
+fn query_pairs(query: Option<&str>) -> Vec<(String, String)> {
+    let mut pairs = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect::<Vec<_>>();
+    pairs.sort();
+    pairs
+}
+
+fn recorded_response(response: HarResponse) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    // Bodies stored as text were decoded by the browser that recorded them, while base64 bodies
+    // are the bytes as they were sent
+    let raw = response.content.encoding.as_deref() == Some("base64");
+    let body = har::decode_text(response.content.text, response.content.encoding.as_deref())?;
+
+    let mut resp = Response::builder().status(response.status as u16);
+    for header in response.headers.iter() {
+        // The body is sent in full so the recorded framing no longer applies, and HTTP/2
+        // exports list pseudo-headers like `:status` that are not valid header names
+        if header.name.eq_ignore_ascii_case("content-length")
+            || header.name.eq_ignore_ascii_case("transfer-encoding")
+            || header.name.eq_ignore_ascii_case("connection")
+            || header.name.starts_with(':')
+            || (!raw && header.name.eq_ignore_ascii_case("content-encoding"))
+        {
+            continue;
+        }
+        resp = resp.header(header.name.as_str(), header.value.as_str());
+    }
+
+    Ok(resp.body(Full::new(body).map_err(|never| match never {}).boxed())?)
+}
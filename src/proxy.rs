@@ -3,11 +3,13 @@ use http_body_util::BodyStream;
 use http_body_util::StreamBody;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::body::{Body, Bytes, Frame};
-use hyper::upgrade::Upgraded;
-use hyper::{Method, Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, Request, Response};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::time::Instant;
+use url::Url;
+use uuid::Uuid;
 
 use futures_util::stream::StreamExt;
 use tokio::sync::broadcast;
@@ -15,8 +17,26 @@ use tokio_stream::wrappers::BroadcastStream;
 
 use crate::config;
 use crate::har;
+use crate::intercept::Intercepted;
+use crate::tunnel;
+use crate::websocket;
 use crate::AppState;
 
+/// Details of the downstream connection a request was received on
+///
+/// The listener adds this to the extensions of every request. Requests that did not come from
+/// a downstream connection, such as replays, do not have it.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+
+    /// Whether the connection uses TLS
+    pub tls: bool,
+}
+
+/// Response header containing the id the transaction is recorded under
+pub const REQUEST_ID_HEADER: &str = "x-park-request-id";
+
 pub async fn proxy<B>(
     config: Arc<config::Config>,
     state: AppState,
@@ -29,6 +49,8 @@ where
     hyper::body::Bytes: From<<B as hyper::body::Body>::Data>,
 {
     tracing::trace!("{:?}", req);
+    let mut timing = har::Timing::start();
+    let request_id = Uuid::now_v7();
 
     if Method::CONNECT == req.method() {
         // Received an HTTP request like:
@@ -44,33 +66,72 @@ where
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
-        if let Some(addr) = host_addr(req.uri()) {
-            tokio::task::spawn(async move {
-                match hyper::upgrade::on(req).await {
-                    Ok(upgraded) => {
-                        if let Err(e) = tunnel(upgraded, addr).await {
-                            eprintln!("server io error: {}", e);
-                        };
-                    }
-                    Err(e) => eprintln!("upgrade error: {}", e),
-                }
-            });
+        tunnel::connect(config, state, req, request_id, timing).await
+    } else {
+        let (head, body) = req.into_parts();
 
-            Ok(Response::new(empty()))
-        } else {
-            eprintln!("CONNECT host is not socket addr: {:?}", req.uri());
-            let mut resp = Response::new(full("CONNECT must be to a socket address"));
-            *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+        let intercepted = head.extensions.get::<Intercepted>();
+        // Requests decrypted from a tunnel already name the server they are meant for
+        let route = match intercepted {
+            Some(_) => None,
+            None => config.route(request_host(&head).as_deref(), head.uri.path()),
+        };
+        let upstream_url = match (intercepted, route) {
+            (Some(intercepted), _) => {
+                upstream_url(&intercepted.origin, head.uri.path(), head.uri.query())
+            }
+            (None, Some(route)) => upstream_url(
+                &route.address,
+                &route.upstream_path(head.uri.path()),
+                head.uri.query(),
+            ),
+            (None, None) => match (&config.server.address, forward_target(&head)) {
+                (Some(address), _) => upstream_url(address, head.uri.path(), head.uri.query()),
+                (None, Some(target)) => {
+                    let host = target.host_str().unwrap_or_default();
+                    if !config.server.allows_destination(host) {
+                        tracing::debug!("Refusing to forward to {}", host);
+                        return Ok(status_response(
+                            http::StatusCode::FORBIDDEN,
+                            "Destination host is not allowed",
+                        ));
+                    }
+                    if is_loop(&config.server.forwarding, &head.headers) {
+                        return Ok(status_response(
+                            http::StatusCode::LOOP_DETECTED,
+                            "Request has already been forwarded by this proxy",
+                        ));
+                    }
 
-            Ok(resp)
+                    target
+                }
+                (None, None) => {
+                    return Ok(status_response(
+                        http::StatusCode::BAD_REQUEST,
+                        "Request must name the host to forward to",
+                    ))
+                }
+            },
+        };
+        let route_name = route.map(config::Route::name);
+
+        if websocket::is_upgrade(&head) {
+            return websocket::proxy(
+                config.clone(),
+                state,
+                head,
+                upstream_url,
+                request_id,
+                route_name,
+                timing,
+            )
+            .await;
         }
-    } else {
-        let mut upstream_url = config.server.address.clone();
 
-        let (head, body) = req.into_parts();
         let (tx, upstream_rx) = broadcast::channel(16);
         let har_rx = tx.subscribe();
 
+        let request_body_sent = timing.request_body_sent.clone();
         tokio::spawn(async move {
             let mut body_stream = BodyStream::new(body);
 
@@ -90,15 +151,19 @@ where
                     }
                 }
             }
+
+            // Stamped before `tx` is dropped so it is set by the time the recorded body ends
+            let _ = request_body_sent.set(Instant::now());
         });
 
         let upstream_stream = BroadcastStream::new(upstream_rx);
 
-        upstream_url.set_path(head.uri.path());
-
         // FIXME: properly upgrade/downgrade HTTP version
         // see https://www.rfc-editor.org/rfc/rfc9110.html#name-to-a-proxy
-        let version = if head.version == http::Version::HTTP_2 && upstream_url.scheme() == "http" {
+        // HTTP/2 on an intercepted tunnel was negotiated with park rather than with the server
+        let version = if head.version == http::Version::HTTP_2
+            && (upstream_url.scheme() == "http" || head.extensions.get::<Intercepted>().is_some())
+        {
             http::Version::HTTP_11
         } else {
             head.version
@@ -108,17 +173,17 @@ where
             .client
             .request(From::from(&head.method), upstream_url)
             .version(version)
-            // FIXME: make sure we are only sending valid headers
-            // examples: HTTP/1.1 Connection header is not allowed for HTTP/2
-            // see https://github.com/hyperium/hyper/discussions/3404
-            .headers(head.headers.clone())
+            .headers(upstream_headers(&config.server.forwarding, &head))
             .body(reqwest::Body::wrap_stream(upstream_stream))
             .build()?;
 
+        timing.request_sent = Instant::now();
         let resp = state.client.execute(upstream_req).await?;
+        timing.response_started = Instant::now();
 
         let resp_status = resp.status();
         let resp_version = resp.version();
+        let server_addr = resp.remote_addr();
         let resp_extension = resp.extensions().clone();
         let resp_headers = resp.headers().clone();
         let mut resp_body = resp.bytes_stream();
@@ -153,10 +218,15 @@ where
             .version(resp_version)
             .extension(resp_extension.clone());
 
-        for (key, value) in resp_headers.iter() {
+        let downstream_headers =
+            downstream_headers(&config.server.forwarding, &resp_headers, resp_version);
+        for (key, value) in downstream_headers.iter() {
             downstream_resp = downstream_resp.header(key, value);
         }
 
+        // Lets clients look up the recording of the request they just made
+        downstream_resp = downstream_resp.header(REQUEST_ID_HEADER, request_id.to_string());
+
         let downstream_resp = downstream_resp.body(downstream_body)?;
 
         tokio::spawn(async move {
@@ -189,8 +259,20 @@ where
                 }
             };
 
-            let har = har::Har::from_transaction(har_req, har_resp).await;
-            let _ = state.har_queue.send(har).await.map_err(|e| {
+            let created_at = timing.started_date_time.timestamp();
+            let mut har = har::Har::from_transaction(har_req, har_resp, timing).await;
+            if let Some(route_name) = route_name {
+                har.set_comment(format!("route: {}", route_name));
+            }
+            if let Some(server_addr) = server_addr {
+                har.set_server_ip_address(server_addr.ip());
+            }
+            let recording = har::Recording {
+                request_id,
+                har,
+                created_at,
+            };
+            let _ = state.har_queue.send(recording).await.map_err(|e| {
                 tracing::error!("Error while queueing HAR: {}", e);
             });
         });
@@ -199,11 +281,233 @@ where
     }
 }
 
-fn host_addr(uri: &http::Uri) -> Option<String> {
-    uri.authority().map(|auth| auth.to_string())
+/// Headers that only apply to a single connection and must not be forwarded
+///
+/// See https://www.rfc-editor.org/rfc/rfc9110.html#section-7.6.1
+const HOP_BY_HOP_HEADERS: [HeaderName; 4] = [
+    header::CONNECTION,
+    header::TE,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Remove hop-by-hop headers, including `Keep-Alive`, every `Proxy-*` header and any header
+/// named in `Connection`
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in HOP_BY_HOP_HEADERS.iter().chain(named.iter()) {
+        headers.remove(name);
+    }
+
+    let proxy_headers = headers
+        .keys()
+        .filter(|name| name.as_str() == "keep-alive" || name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in proxy_headers {
+        headers.remove(name);
+    }
+}
+
+/// Append park to the `Via` header of a message received with the given version
+fn append_via(headers: &mut HeaderMap, version: http::Version, pseudonym: &str) {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+
+    append_header(headers, header::VIA, &format!("{} {}", protocol, pseudonym));
+}
+
+/// Add `value` to the end of a comma separated list header
+///
+/// A header sent on several lines is folded into one so the order of the list is kept.
+fn append_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let value = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .chain([value])
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(name, value);
+    }
+}
+
+/// The headers to send downstream for an upstream response
+pub fn downstream_headers(
+    forwarding: &config::Forwarding,
+    headers: &HeaderMap,
+    version: http::Version,
+) -> HeaderMap {
+    let mut headers = headers.clone();
+    remove_hop_by_hop(&mut headers);
+    if forwarding.via {
+        append_via(&mut headers, version, &forwarding.pseudonym);
+    }
+
+    headers
 }
 
-fn empty() -> BoxBody<Bytes, anyhow::Error> {
+/// The headers to send upstream for a downstream request
+pub fn upstream_headers(forwarding: &config::Forwarding, head: &http::request::Parts) -> HeaderMap {
+    let mut headers = head.headers.clone();
+    remove_hop_by_hop(&mut headers);
+
+    let host = match head.uri.authority() {
+        Some(authority) => Some(authority.to_string()),
+        None => headers
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    };
+    // The upstream client sets the host of the upstream server when there is no Host header
+    if !forwarding.preserve_host {
+        headers.remove(header::HOST);
+    }
+
+    let connection = head.extensions.get::<ConnectionInfo>();
+    let proto = match connection {
+        Some(connection) if connection.tls => "https",
+        _ => "http",
+    };
+
+    if forwarding.via {
+        append_via(&mut headers, head.version, &forwarding.pseudonym);
+    }
+
+    if forwarding.x_forwarded {
+        if let Some(connection) = connection {
+            append_header(
+                &mut headers,
+                HeaderName::from_static("x-forwarded-for"),
+                &connection.remote_addr.ip().to_string(),
+            );
+        }
+        headers.insert(
+            HeaderName::from_static("x-forwarded-proto"),
+            HeaderValue::from_static(proto),
+        );
+        if let Some(value) = host.as_ref().and_then(|h| HeaderValue::from_str(h).ok()) {
+            headers.insert(HeaderName::from_static("x-forwarded-host"), value);
+        }
+    }
+
+    if forwarding.forwarded {
+        // See https://www.rfc-editor.org/rfc/rfc7239#section-4
+        let mut element = Vec::new();
+        if let Some(connection) = connection {
+            match connection.remote_addr.ip() {
+                IpAddr::V4(ip) => element.push(format!("for={}", ip)),
+                IpAddr::V6(ip) => element.push(format!("for=\"[{}]\"", ip)),
+            }
+        }
+        if let Some(host) = &host {
+            element.push(format!("host=\"{}\"", host));
+        }
+        element.push(format!("proto={}", proto));
+
+        append_header(&mut headers, header::FORWARDED, &element.join(";"));
+    }
+
+    headers
+}
+
+/// Append the request path and query to the upstream address
+///
+/// Any path of the upstream address is kept as a prefix, so a request for `/users?id=1` sent to
+/// `http://host/api/v2` is forwarded to `http://host/api/v2/users?id=1`. The query of the upstream
+/// address is replaced by the query of the request. Percent-encoded characters are forwarded as
+/// is.
+fn upstream_url(address: &Url, path: &str, query: Option<&str>) -> Url {
+    let mut url = address.clone();
+
+    let base = address.path().trim_end_matches('/');
+    if path.starts_with('/') {
+        url.set_path(&format!("{}{}", base, path));
+    } else {
+        url.set_path(&format!("{}/{}", base, path));
+    }
+    url.set_query(query);
+
+    url
+}
+
+/// Host name of the request without the port
+///
+/// Taken from the URI for absolute-form and HTTP/2 requests, otherwise from the `Host` header.
+fn request_host(head: &http::request::Parts) -> Option<String> {
+    let authority = match head.uri.authority() {
+        Some(authority) => authority.clone(),
+        None => head
+            .headers
+            .get(http::header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<http::uri::Authority>()
+            .ok()?,
+    };
+
+    Some(authority.host().to_string())
+}
+
+/// The URL a request to a forward proxy is meant for
+///
+/// Taken from an absolute-form URI, otherwise from the `Host` header of a plain HTTP request.
+fn forward_target(head: &http::request::Parts) -> Option<Url> {
+    let authority = match head.uri.authority() {
+        Some(authority) => authority.as_str(),
+        None => head.headers.get(http::header::HOST)?.to_str().ok()?,
+    };
+    let scheme = match head.uri.scheme_str() {
+        Some(scheme @ ("http" | "https")) => scheme,
+        Some(_) => return None,
+        None => "http",
+    };
+
+    let origin = Url::parse(&format!("{}://{}", scheme, authority)).ok()?;
+    origin
+        .has_host()
+        .then(|| upstream_url(&origin, head.uri.path(), head.uri.query()))
+}
+
+/// Whether park already forwarded the request, going by its pseudonym in the `Via` header
+///
+/// A forward proxy asked to send a request to itself would otherwise do so forever.
+fn is_loop(forwarding: &config::Forwarding, headers: &HeaderMap) -> bool {
+    forwarding.via
+        && headers
+            .get_all(header::VIA)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|via| via.split_whitespace().nth(1))
+            .any(|received_by| received_by == forwarding.pseudonym)
+}
+
+pub fn status_response(
+    status: http::StatusCode,
+    message: &'static str,
+) -> Response<BoxBody<Bytes, anyhow::Error>> {
+    let mut resp = Response::new(full(message));
+    *resp.status_mut() = status;
+
+    resp
+}
+
+pub fn empty() -> BoxBody<Bytes, anyhow::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
@@ -214,23 +518,3 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, anyhow::Error> {
         .map_err(|never| match never {})
         .boxed()
 }
-
-// Create a TCP connection to host:port, build a tunnel between the connection and
-// the upgraded connection
-async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
-    // Connect to remote server
-    let mut server = TcpStream::connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
-
-    // Proxying data
-    let (from_client, from_server) =
-        tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
-
-    // Print message when done
-    println!(
-        "client wrote {} bytes and received {} bytes",
-        from_client, from_server
-    );
-
-    Ok(())
-}
@@ -0,0 +1,308 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::config;
+use crate::db;
+use crate::har::{self, Har};
+use crate::proxy::{proxy, REQUEST_ID_HEADER};
+use crate::AppState;
+
+/// Selects the stored requests to replay and how their responses are compared
+#[derive(Debug, Default, Deserialize)]
+pub struct ReplayOptions {
+    /// Uses the same query string format as `GET /requests`. Every request is replayed when empty.
+    #[serde(default)]
+    pub filter: String,
+
+    /// Response headers to compare in addition to the status and body
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    /// JSON fields to skip when comparing bodies, such as timestamps
+    ///
+    /// Either a field name, which is skipped wherever it appears, or a full path such as
+    /// `$.meta.generated_at` or `$.items[0]`. `[*]` in a path matches every array element, as in
+    /// `$.items[*].updated_at`.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub replayed: usize,
+    pub unchanged: usize,
+    pub changed: usize,
+    pub failed: usize,
+
+    /// Results of the requests that changed or failed
+    pub results: Vec<ReplayResult>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayResult {
+    pub request_id: String,
+
+    /// Id the replayed transaction is recorded under
+    pub replay_request_id: Option<String>,
+
+    pub method: String,
+
+    pub url: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Difference>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<Difference>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub body: Vec<Difference>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ReplayResult {
+    fn changed(&self) -> bool {
+        self.status.is_some() || !self.headers.is_empty() || !self.body.is_empty()
+    }
+}
+
+/// A value that differs between the recorded and the replayed response
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    /// `status`, a header name, or the JSON path of a body value. `$` refers to the whole body.
+    pub path: String,
+
+    /// `None` if the value is missing from the recorded response
+    pub recorded: Option<Value>,
+
+    /// `None` if the value is missing from the replayed response
+    pub replayed: Option<Value>,
+}
+
+/// Replay every stored request selected by `options` and compare the responses
+///
+/// Replayed transactions are recorded like any other proxied request. Returns once all of them
+/// have been written to the database.
+pub async fn replay(config: config::Config, options: ReplayOptions) -> Result<Report> {
+    let (state, writer) = crate::app_with_writer(&config).await?;
+
+    let report = run(Arc::new(config), state, &options).await;
+    writer.join().await?;
+
+    report
+}
+
+/// Replay every stored request selected by `options` and compare the responses
+pub async fn run(
+    config: Arc<config::Config>,
+    state: AppState,
+    options: &ReplayOptions,
+) -> Result<Report> {
+    let filter = db::RequestFilter::from_query(&options.filter).map_err(anyhow::Error::msg)?;
+
+    // The ids are collected up front so the rows recorded while replaying are not selected
+    let request_ids = db::request_ids(&state.db, &filter).await?;
+
+    let mut report = Report::default();
+    for request_id in request_ids {
+        let mut result = ReplayResult {
+            request_id: request_id.to_string(),
+            ..Default::default()
+        };
+
+        match db::get_request(&state.db, request_id).await {
+            Ok(Some(har)) => {
+                if let Some(request) = har.request() {
+                    result.method = request.method.clone();
+                    result.url = request.url.clone();
+                }
+
+                if let Err(err) =
+                    replay_one(config.clone(), state.clone(), har, options, &mut result).await
+                {
+                    tracing::debug!("Failed to replay {}: {:?}", request_id, err);
+                    result.error = Some(err.to_string());
+                }
+            }
+            // Deleted after the ids were collected
+            Ok(None) => continue,
+            Err(err) => {
+                tracing::debug!("Failed to load {}: {:?}", request_id, err);
+                result.error = Some(format!("Failed to load the recording: {}", err));
+            }
+        }
+
+        report.replayed += 1;
+        if result.error.is_some() {
+            report.failed += 1;
+            report.results.push(result);
+        } else if result.changed() {
+            report.changed += 1;
+            report.results.push(result);
+        } else {
+            report.unchanged += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+async fn replay_one(
+    config: Arc<config::Config>,
+    state: AppState,
+    har: Har,
+    options: &ReplayOptions,
+    result: &mut ReplayResult,
+) -> Result<()> {
+    let recorded = har
+        .response()
+        .cloned()
+        .ok_or_else(|| anyhow!("Recording has no entries"))?;
+
+    let req = hyper::Request::try_from(har)?;
+    let res = proxy(config, state, req).await?;
+    let (parts, body) = res.into_parts();
+    let body = body.collect().await?.to_bytes();
+
+    result.replay_request_id = parts
+        .headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .map(|id| id.to_string());
+
+    let status = parts.status.as_u16() as i64;
+    if recorded.status != status {
+        result.status = Some(Difference {
+            path: "status".to_string(),
+            recorded: Some(recorded.status.into()),
+            replayed: Some(status.into()),
+        });
+    }
+
+    for name in options.headers.iter() {
+        let recorded = recorded
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.clone())
+            .collect::<Vec<_>>();
+        let replayed = parts
+            .headers
+            .get_all(name.as_str())
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+            .collect::<Vec<_>>();
+
+        if recorded != replayed {
+            result.headers.push(Difference {
+                path: name.clone(),
+                recorded: (!recorded.is_empty()).then(|| recorded.join(", ").into()),
+                replayed: (!replayed.is_empty()).then(|| replayed.join(", ").into()),
+            });
+        }
+    }
+
+    let recorded_body =
+        har::decode_text(recorded.content.text, recorded.content.encoding.as_deref())?;
+    match (
+        serde_json::from_slice::<Value>(&recorded_body),
+        serde_json::from_slice::<Value>(&body),
+    ) {
+        (Ok(recorded), Ok(replayed)) => {
+            diff_json("$", &recorded, &replayed, &options.ignore, &mut result.body)
+        }
+        _ if recorded_body != body => result.body.push(Difference {
+            path: "$".to_string(),
+            recorded: Some(String::from_utf8_lossy(&recorded_body).into()),
+            replayed: Some(String::from_utf8_lossy(&body).into()),
+        }),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Collect the paths of every value that differs between two JSON documents
+fn diff_json(
+    path: &str,
+    recorded: &Value,
+    replayed: &Value,
+    ignore: &[String],
+    differences: &mut Vec<Difference>,
+) {
+    match (recorded, replayed) {
+        (Value::Object(recorded), Value::Object(replayed)) => {
+            let keys = recorded
+                .keys()
+                .chain(replayed.keys().filter(|key| !recorded.contains_key(*key)));
+
+            for key in keys {
+                let child = format!("{}.{}", path, key);
+                if ignore.iter().any(|i| i == key || path_matches(i, &child)) {
+                    continue;
+                }
+
+                match (recorded.get(key), replayed.get(key)) {
+                    (Some(recorded), Some(replayed)) => {
+                        diff_json(&child, recorded, replayed, ignore, differences)
+                    }
+                    (recorded, replayed) => differences.push(Difference {
+                        path: child,
+                        recorded: recorded.cloned(),
+                        replayed: replayed.cloned(),
+                    }),
+                }
+            }
+        }
+        (Value::Array(recorded), Value::Array(replayed)) => {
+            for i in 0..recorded.len().max(replayed.len()) {
+                let child = format!("{}[{}]", path, i);
+                if ignore.iter().any(|i| path_matches(i, &child)) {
+                    continue;
+                }
+
+                match (recorded.get(i), replayed.get(i)) {
+                    (Some(recorded), Some(replayed)) => {
+                        diff_json(&child, recorded, replayed, ignore, differences)
+                    }
+                    (recorded, replayed) => differences.push(Difference {
+                        path: child,
+                        recorded: recorded.cloned(),
+                        replayed: replayed.cloned(),
+                    }),
+                }
+            }
+        }
+        (recorded, replayed) if recorded != replayed => differences.push(Difference {
+            path: path.to_string(),
+            recorded: Some(recorded.clone()),
+            replayed: Some(replayed.clone()),
+        }),
+        _ => {}
+    }
+}
+
+/// Whether a JSON path such as `$.items[3].id` matches an ignored path, where `[*]` matches any
+/// array index
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.split_once("[*]") {
+        None => pattern == path,
+        Some((prefix, rest)) => path
+            .strip_prefix(prefix)
+            .and_then(|path| path.strip_prefix('['))
+            .and_then(|path| path.split_once(']'))
+            .is_some_and(|(index, path)| {
+                !index.is_empty()
+                    && index.bytes().all(|b| b.is_ascii_digit())
+                    && path_matches(rest, path)
+            }),
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use http_body_util::{combinators::BoxBody, Empty};
+use hyper::body::Bytes;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::config;
+use crate::har;
+use crate::intercept::intercept;
+use crate::proxy::{empty, status_response, ConnectionInfo};
+use crate::AppState;
+
+/// Open a tunnel for a CONNECT request, or intercept the traffic sent through it when a
+/// certificate authority is configured
+///
+/// The target must be allowed by `server.destinations`. Tunnels are recorded once they close, as
+/// are CONNECT requests that are refused or fail to connect.
+pub async fn connect<B>(
+    config: Arc<config::Config>,
+    state: AppState,
+    mut req: Request<B>,
+    request_id: Uuid,
+    mut timing: har::Timing,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let (head, _) = req.into_parts();
+
+    let Some((authority, port)) = head
+        .uri
+        .authority()
+        .and_then(|authority| Some((authority.clone(), authority.port_u16()?)))
+    else {
+        tracing::warn!("CONNECT host is not socket addr: {:?}", head.uri);
+        return Ok(status_response(
+            StatusCode::BAD_REQUEST,
+            "CONNECT must be to a socket address",
+        ));
+    };
+
+    if !config.server.allows_tunnel(authority.host(), port) {
+        tracing::debug!("Refusing to open a tunnel to {}", authority);
+        record(state, request_id, head, StatusCode::FORBIDDEN, timing).await;
+        return Ok(status_response(
+            StatusCode::FORBIDDEN,
+            "Destination is not allowed",
+        ));
+    }
+
+    let idle_timeout = Duration::from_secs(config.server.tunnel.idle_timeout);
+
+    // The requests decrypted from an intercepted tunnel are sent by the upstream client, so
+    // `server.server_timeout` applies to them instead of the connect timeout
+    if let Some(ca) = state.certificate_authority.clone() {
+        let connection = head.extensions.get::<ConnectionInfo>().copied();
+        let created_at = timing.started_date_time.timestamp();
+        let har = transaction(head, StatusCode::OK, timing).await;
+
+        tokio::spawn(async move {
+            let opened = Instant::now();
+            let tunnel = match on_upgrade.await {
+                Ok(upgraded) => {
+                    let addr = authority.to_string();
+                    let state = state.clone();
+                    intercept(config, state, ca, upgraded, addr, connection, idle_timeout).await
+                }
+                Err(e) => {
+                    tracing::error!("CONNECT upgrade error: {}", e);
+                    upgrade_failed(e)
+                }
+            };
+
+            closed(
+                state, request_id, &authority, har, tunnel, opened, created_at,
+            )
+            .await;
+        });
+
+        return Ok(Response::new(empty()));
+    }
+
+    // Connect before answering so the client learns when the target cannot be reached
+    timing.request_sent = Instant::now();
+    let connect_timeout = Duration::from_secs(config.server.tunnel.connect_timeout);
+    let server =
+        tokio::time::timeout(connect_timeout, TcpStream::connect(authority.as_str())).await;
+    timing.response_started = Instant::now();
+
+    let (status, message) = match server {
+        Ok(Ok(server)) => {
+            let created_at = timing.started_date_time.timestamp();
+            let mut har = transaction(head, StatusCode::OK, timing).await;
+            if let Ok(server_addr) = server.peer_addr() {
+                har.set_server_ip_address(server_addr.ip());
+            }
+
+            tokio::spawn(async move {
+                let opened = Instant::now();
+                let tunnel = match on_upgrade.await {
+                    Ok(upgraded) => splice(TokioIo::new(upgraded), server, idle_timeout).await,
+                    Err(e) => upgrade_failed(e),
+                };
+
+                closed(
+                    state, request_id, &authority, har, tunnel, opened, created_at,
+                )
+                .await;
+            });
+
+            return Ok(Response::new(empty()));
+        }
+        Ok(Err(e)) => {
+            tracing::debug!("Failed to connect to {}: {}", authority, e);
+            (
+                StatusCode::BAD_GATEWAY,
+                "Failed to connect to the destination",
+            )
+        }
+        Err(_) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "Timed out connecting to the destination",
+        ),
+    };
+
+    record(state, request_id, head, status, timing).await;
+
+    Ok(status_response(status, message))
+}
+
+/// Copy bytes in both directions until both sides have closed, either side fails or no data is
+/// sent for `idle_timeout`
+async fn splice<C>(client: C, mut server: TcpStream, idle_timeout: Duration) -> har::Tunnel
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut server_read, mut server_write) = server.split();
+    let mut client_buf = vec![0; 8 * 1024];
+    let mut server_buf = vec![0; 8 * 1024];
+
+    let mut tunnel = har::Tunnel {
+        bytes_sent: 0,
+        bytes_received: 0,
+        close_reason: String::new(),
+    };
+    let (mut client_open, mut server_open) = (true, true);
+    let mut closed_first = None;
+
+    tunnel.close_reason = loop {
+        if !client_open && !server_open {
+            break closed_first.unwrap_or("closed").to_string();
+        }
+
+        // The idle timer starts over whenever either side sends data
+        tokio::select! {
+            read = client_read.read(&mut client_buf), if client_open => match read {
+                Ok(0) => {
+                    client_open = false;
+                    closed_first.get_or_insert("client closed");
+                    let _ = server_write.shutdown().await;
+                }
+                Ok(n) => {
+                    if let Err(e) = server_write.write_all(&client_buf[..n]).await {
+                        break format!("server error: {}", e);
+                    }
+                    tunnel.bytes_sent += n as u64;
+                }
+                Err(e) => break format!("client error: {}", e),
+            },
+            read = server_read.read(&mut server_buf), if server_open => match read {
+                Ok(0) => {
+                    server_open = false;
+                    closed_first.get_or_insert("server closed");
+                    let _ = client_write.shutdown().await;
+                }
+                Ok(n) => {
+                    if let Err(e) = client_write.write_all(&server_buf[..n]).await {
+                        break format!("client error: {}", e);
+                    }
+                    tunnel.bytes_received += n as u64;
+                }
+                Err(e) => break format!("server error: {}", e),
+            },
+            _ = tokio::time::sleep(idle_timeout) => break "idle timeout".to_string(),
+        }
+    };
+
+    tunnel
+}
+
+/// The tunnel of a CONNECT request whose connection could not be upgraded
+fn upgrade_failed(e: hyper::Error) -> har::Tunnel {
+    har::Tunnel {
+        bytes_sent: 0,
+        bytes_received: 0,
+        close_reason: format!("upgrade error: {}", e),
+    }
+}
+
+/// Record a tunnel that was opened at `opened` and has closed
+async fn closed(
+    state: AppState,
+    request_id: Uuid,
+    authority: &http::uri::Authority,
+    mut har: har::Har,
+    tunnel: har::Tunnel,
+    opened: Instant,
+    created_at: i64,
+) {
+    tracing::debug!(
+        "Tunnel to {} closed: {} bytes sent, {} bytes received, {}",
+        authority,
+        tunnel.bytes_sent,
+        tunnel.bytes_received,
+        tunnel.close_reason
+    );
+
+    let _ = har.set_tunnel(tunnel, opened.elapsed()).inspect_err(|e| {
+        tracing::error!("Error while recording tunnel: {}", e);
+    });
+    queue(state, request_id, har, created_at).await;
+}
+
+/// Record a CONNECT request that was answered with `status` without opening a tunnel
+async fn record(
+    state: AppState,
+    request_id: Uuid,
+    head: http::request::Parts,
+    status: StatusCode,
+    timing: har::Timing,
+) {
+    let created_at = timing.started_date_time.timestamp();
+    let har = transaction(head, status, timing).await;
+
+    queue(state, request_id, har, created_at).await;
+}
+
+/// The HAR of a CONNECT request answered with `status`
+///
+/// CONNECT requests and their responses have no body so the HAR is complete once the response
+/// has been sent.
+async fn transaction(
+    head: http::request::Parts,
+    status: StatusCode,
+    timing: har::Timing,
+) -> har::Har {
+    let req = Request::from_parts(head, Empty::<Bytes>::new());
+    let mut resp = Response::new(Empty::<Bytes>::new());
+    *resp.status_mut() = status;
+
+    har::Har::from_transaction(req, resp, timing).await
+}
+
+async fn queue(state: AppState, request_id: Uuid, har: har::Har, created_at: i64) {
+    let recording = har::Recording {
+        request_id,
+        har,
+        created_at,
+    };
+    let _ = state.har_queue.send(recording).await.inspect_err(|e| {
+        tracing::error!("Error while queueing HAR: {}", e);
+    });
+}
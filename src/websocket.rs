@@ -0,0 +1,283 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use futures_util::future::join;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderValue};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use url::Url;
+use uuid::Uuid;
+
+use crate::config;
+use crate::har::{self, WebSocketMessage};
+use crate::proxy::{downstream_headers, upstream_headers, REQUEST_ID_HEADER};
+use crate::AppState;
+
+/// Whether the request asks to upgrade the connection to a WebSocket
+///
+/// Only HTTP/1.1 upgrades are supported. WebSockets over HTTP/2 use an extended CONNECT.
+pub fn is_upgrade(head: &http::request::Parts) -> bool {
+    let has_token = |name: header::HeaderName, token: &str| {
+        head.headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    head.version == http::Version::HTTP_11
+        && has_token(header::CONNECTION, "upgrade")
+        && has_token(header::UPGRADE, "websocket")
+}
+
+/// Forward a WebSocket handshake upstream and relay frames between the two connections
+///
+/// The handshake is recorded once both sides have closed the connection, with the frames stored
+/// in the `_webSocketMessages` field of the entry up to the limits in `server.websocket`. A
+/// handshake the upstream server
+/// declines is recorded like any other response.
+pub async fn proxy(
+    config: Arc<config::Config>,
+    state: AppState,
+    mut head: http::request::Parts,
+    upstream_url: Url,
+    request_id: Uuid,
+    route_name: Option<String>,
+    mut timing: har::Timing,
+) -> Result<Response<BoxBody<Bytes, anyhow::Error>>> {
+    let on_upgrade = head.extensions.remove::<OnUpgrade>();
+
+    let mut headers = upstream_headers(&config.server.forwarding, &head);
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    // Frames are relayed one message at a time so extensions that change the framing, such as
+    // permessage-deflate, cannot be negotiated end to end
+    headers.remove(header::SEC_WEBSOCKET_EXTENSIONS);
+
+    let upstream_req = state
+        .client
+        .get(upstream_url)
+        .version(http::Version::HTTP_11)
+        .headers(headers)
+        .build()?;
+
+    timing.request_sent = Instant::now();
+    let resp = state.client.execute(upstream_req).await?;
+    timing.response_started = Instant::now();
+
+    let status = resp.status();
+    let version = resp.version();
+    let server_addr = resp.remote_addr();
+    let resp_headers = resp.headers().clone();
+
+    let mut downstream_resp = Response::builder().status(status).version(version);
+    for (key, value) in downstream_headers(&config.server.forwarding, &resp_headers, version).iter()
+    {
+        downstream_resp = downstream_resp.header(key, value);
+    }
+    downstream_resp = downstream_resp.header(REQUEST_ID_HEADER, request_id.to_string());
+
+    let record = move |har_resp: Response<Full<Bytes>>, messages| async move {
+        let created_at = timing.started_date_time.timestamp();
+        let har_req = Request::from_parts(head, Empty::<Bytes>::new());
+        let mut har = har::Har::from_transaction(har_req, har_resp, timing).await;
+        if let Some(route_name) = route_name {
+            har.set_comment(format!("route: {}", route_name));
+        }
+        if let Some(server_addr) = server_addr {
+            har.set_server_ip_address(server_addr.ip());
+        }
+        if let Some((messages, omitted)) = messages {
+            let _ = har
+                .set_web_socket_messages(messages, omitted)
+                .inspect_err(|e| {
+                    tracing::error!("Error while recording WebSocket messages: {}", e);
+                });
+        }
+        let recording = har::Recording {
+            request_id,
+            har,
+            created_at,
+        };
+        let _ = state.har_queue.send(recording).await.inspect_err(|e| {
+            tracing::error!("Error while queueing HAR: {}", e);
+        });
+    };
+
+    let mut har_resp = Response::builder().status(status).version(version);
+    for (key, value) in resp_headers.iter() {
+        har_resp = har_resp.header(key, value);
+    }
+
+    let on_upgrade = match on_upgrade {
+        Some(on_upgrade) if status == StatusCode::SWITCHING_PROTOCOLS => on_upgrade,
+        _ => {
+            let body = resp.bytes().await?;
+            let har_resp = har_resp.body(Full::new(body.clone()))?;
+            tokio::spawn(record(har_resp, None));
+
+            return Ok(
+                downstream_resp.body(Full::new(body).map_err(|never| match never {}).boxed())?
+            );
+        }
+    };
+
+    let downstream_resp = downstream_resp
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .body(Empty::new().map_err(|never| match never {}).boxed())?;
+    let har_resp = har_resp.body(Full::new(Bytes::new()))?;
+
+    tokio::spawn(async move {
+        // The downstream connection is only upgraded once the 101 response has been sent
+        let (client, server) = match tokio::try_join!(
+            async { on_upgrade.await.map_err(anyhow::Error::from) },
+            async { resp.upgrade().await.map_err(anyhow::Error::from) },
+        ) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                tracing::error!("WebSocket upgrade error: {}", e);
+                record(har_resp, None).await;
+                return;
+            }
+        };
+
+        let client =
+            WebSocketStream::from_raw_socket(TokioIo::new(client), Role::Server, None).await;
+        let server = WebSocketStream::from_raw_socket(server, Role::Client, None).await;
+
+        let (client_tx, client_rx) = client.split();
+        let (server_tx, server_rx) = server.split();
+        let recorder = Recorder::new(&config.server.websocket);
+        join(
+            relay(client_rx, server_tx, "send", &recorder),
+            relay(server_rx, client_tx, "receive", &recorder),
+        )
+        .await;
+
+        record(har_resp, Some(recorder.finish())).await;
+    });
+
+    Ok(downstream_resp)
+}
+
+/// Forward messages from one side of the proxy to the other until the connection closes
+///
+/// Every frame read, including pings and pongs, is passed to `recorder`. Pings and pongs are
+/// answered by the side of the proxy that received them instead of being forwarded.
+async fn relay<R, W>(mut from: R, mut to: W, kind: &str, recorder: &Recorder)
+where
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+    W: Sink<Message, Error = WsError> + Unpin,
+{
+    while let Some(message) = from.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::debug!("WebSocket {} error: {}", kind, e);
+                break;
+            }
+        };
+        recorder.record(kind, &message);
+
+        match message {
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            message => {
+                if to.send(message).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = to.close().await;
+}
+
+/// Keeps the frames of a WebSocket connection for its recording
+///
+/// Frames past `max_messages` or `max_bytes` of payload are only counted, so a long-lived
+/// connection does not grow without limit.
+struct Recorder {
+    max_messages: usize,
+    max_bytes: usize,
+    recorded: Mutex<Recorded>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    messages: Vec<WebSocketMessage>,
+    bytes: usize,
+    omitted: u64,
+}
+
+impl Recorder {
+    fn new(config: &config::WebSocket) -> Self {
+        Recorder {
+            max_messages: config.max_messages,
+            max_bytes: config.max_bytes,
+            recorded: Mutex::default(),
+        }
+    }
+
+    fn record(&self, kind: &str, message: &Message) {
+        let mut recorded = self.recorded.lock().unwrap();
+
+        let bytes = recorded.bytes + message.len();
+        if recorded.messages.len() >= self.max_messages || bytes > self.max_bytes {
+            recorded.omitted += 1;
+            return;
+        }
+
+        recorded.messages.push(web_socket_message(kind, message));
+        recorded.bytes = bytes;
+    }
+
+    /// The recorded frames in the order they were received and the number of omitted frames
+    fn finish(self) -> (Vec<WebSocketMessage>, u64) {
+        let recorded = self.recorded.into_inner().unwrap();
+
+        (recorded.messages, recorded.omitted)
+    }
+}
+
+fn web_socket_message(kind: &str, message: &Message) -> WebSocketMessage {
+    let (opcode, data) = match message {
+        Message::Text(text) => (1, text.clone()),
+        Message::Binary(data) => (2, BASE64.encode(data)),
+        Message::Close(frame) => {
+            // The payload of a close frame is the status code followed by the reason
+            let payload = frame
+                .as_ref()
+                .map(|frame| {
+                    let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+                    payload.extend_from_slice(frame.reason.as_bytes());
+                    payload
+                })
+                .unwrap_or_default();
+            (8, BASE64.encode(payload))
+        }
+        Message::Ping(data) => (9, BASE64.encode(data)),
+        Message::Pong(data) => (10, BASE64.encode(data)),
+        Message::Frame(frame) => (
+            u8::from(frame.header().opcode),
+            BASE64.encode(frame.payload()),
+        ),
+    };
+
+    WebSocketMessage {
+        kind: kind.to_string(),
+        time: Utc::now().timestamp_micros() as f64 / 1_000_000.0,
+        opcode,
+        data,
+    }
+}
@@ -0,0 +1,177 @@
+//! Fixtures shared by the integration tests
+
+// Each test binary only uses some of the fixtures
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+/// A start time for entries whose start time does not matter
+pub const STARTED: &str = "2024-01-01T00:00:00.000Z";
+
+/// A directory no other test uses, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("park-{}-{}", name, Uuid::now_v7()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The URI of a database file in the directory
+    pub fn database(&self) -> String {
+        format!("sqlite:{}?mode=rwc", self.0.join("park.db").display())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Start an upstream server that responds with the path and query it received
+pub async fn upstream() -> SocketAddr {
+    delayed_upstream(Duration::ZERO).await
+}
+
+/// Start an upstream server that waits `delay` before responding with the path and query it
+/// received
+pub async fn delayed_upstream(delay: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    tokio::time::sleep(delay).await;
+                    let target = req
+                        .uri()
+                        .path_and_query()
+                        .map(|pq| pq.as_str().to_string())
+                        .unwrap_or_default();
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(target))))
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// A configuration proxying to an unused address with the database at `uri`
+///
+/// `database` holds more keys for the `[database]` section and `sections` any other sections.
+pub fn config(uri: &str, database: &str, sections: &str) -> park::Config {
+    toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "{uri}"
+        {database}
+
+        [server]
+        address = "http://127.0.0.1:1"
+
+        {sections}
+        "#
+    ))
+    .unwrap()
+}
+
+/// A HAR entry for a request to `path` started at `started` that was answered with
+/// `response_body`
+///
+/// The request is a POST with `request_body` when one is given and a GET otherwise.
+pub fn entry(path: &str, started: &str, request_body: Option<&str>, response_body: &str) -> Value {
+    let mut request = json!({
+        "method": if request_body.is_some() { "POST" } else { "GET" },
+        "url": format!("http://localhost{path}"),
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": [],
+        "queryString": [],
+        "headersSize": -1,
+        "bodySize": request_body.map_or(0, str::len)
+    });
+    if let Some(text) = request_body {
+        request["postData"] = json!({ "mimeType": "text/plain", "text": text });
+    }
+
+    json!({
+        "startedDateTime": started,
+        "time": 1.0,
+        "request": request,
+        "response": {
+            "status": 200,
+            "statusText": "OK",
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": [],
+            "content": {
+                "size": response_body.len(),
+                "mimeType": "text/plain",
+                "text": response_body
+            },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": response_body.len()
+        },
+        "cache": {},
+        "timings": { "send": 0.0, "wait": 0.0, "receive": 0.0 }
+    })
+}
+
+/// A HAR log holding `entries` as park stores it, without the `{"log": ...}` wrapper
+pub fn log(entries: Vec<Value>) -> Value {
+    json!({
+        "creator": { "name": "park", "version": "0.1.0" },
+        "entries": entries
+    })
+}
+
+/// A HAR file holding `entries`
+pub fn har_file(entries: Vec<Value>) -> Value {
+    let mut log = log(entries);
+    log["version"] = json!("1.2");
+
+    json!({ "log": log })
+}
+
+/// A recording of a single entry as the proxy queues it
+pub fn recording(entry: Value) -> park::Recording {
+    serde_json::from_value(json!({
+        "request_id": Uuid::now_v7(),
+        "created_at": 0,
+        "har": log(vec![entry])
+    }))
+    .unwrap()
+}
+
+/// Import `har` with `park import`
+pub async fn import(config: &park::Config, dir: &TempDir, har: Value) {
+    let file = dir.path().join(format!("{}.har", Uuid::now_v7()));
+    std::fs::write(&file, har.to_string()).unwrap();
+    park::import(config, &file).await.unwrap();
+}
@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use sqlx::SqlitePool;
+use tokio::io::AsyncReadExt;
+
+mod common;
+
+use common::TempDir;
+
+async fn count(db: &SqlitePool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM requests")
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn slow_export_does_not_block_recording() {
+    let dir = TempDir::new("export");
+    let config = common::config(&dir.database(), "", "");
+    let (state, _writer) = park::app_with_writer(&config).await.unwrap();
+
+    // More requests than one page of the export, each too large to fit in the pipe
+    let body = "x".repeat(4096);
+    let entries = (0..250)
+        .map(|n| {
+            let started = format!("2024-01-01T00:{:02}:{:02}.000Z", n / 60, n % 60);
+            common::entry(&format!("/{n}"), &started, None, &body)
+        })
+        .collect();
+    common::import(&config, &dir, common::har_file(entries)).await;
+
+    // Nothing reads the export until a new recording has been stored
+    let (mut reader, writer) = tokio::io::duplex(1024);
+    let export_config = common::config(&dir.database(), "", "");
+    let export = tokio::spawn(async move { park::export(&export_config, "", false, writer).await });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!export.is_finished());
+
+    let recording = common::recording(common::entry("/live", common::STARTED, None, ""));
+    state.har_queue.send(recording).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while count(&state.db).await < 251 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("recording was not stored while the export was paused");
+
+    let mut exported = Vec::new();
+    reader.read_to_end(&mut exported).await.unwrap();
+    export.await.unwrap().unwrap();
+    let exported: Value = serde_json::from_slice(&exported).unwrap();
+    let entries = exported["log"]["entries"].as_array().unwrap();
+    assert!(entries.len() >= 250);
+    assert_eq!(entries[0]["request"]["url"], "http://localhost/0");
+    assert_eq!(entries[249]["request"]["url"], "http://localhost/249");
+}
@@ -0,0 +1,38 @@
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+mod common;
+
+use common::TempDir;
+
+#[tokio::test]
+async fn failed_import_stores_nothing() {
+    let dir = TempDir::new("import");
+    let config = common::config(&dir.database(), "", "");
+    park::migrate(&config, false).await.unwrap();
+
+    // Fail on an entry past the first batch so earlier batches have already been inserted
+    let db = SqlitePool::connect(&config.database.uri).await.unwrap();
+    sqlx::query(
+        r#"
+        CREATE TRIGGER fail_import BEFORE INSERT ON requests WHEN NEW.path = '/150'
+        BEGIN SELECT RAISE(ABORT, 'failed'); END
+        "#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let entries = (0..200)
+        .map(|n| common::entry(&format!("/{n}"), common::STARTED, None, ""))
+        .collect();
+    let file = dir.path().join(format!("{}.har", Uuid::now_v7()));
+    std::fs::write(&file, common::har_file(entries).to_string()).unwrap();
+    assert!(park::import(&config, &file).await.is_err());
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM requests")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
@@ -0,0 +1,76 @@
+use serde_json::Value;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+mod common;
+
+use common::TempDir;
+
+#[tokio::test]
+async fn compresses_inline_bodies() {
+    let dir = TempDir::new("migrate");
+    let config = common::config(&dir.database(), "", "");
+    park::migrate(&config, false).await.unwrap();
+
+    let db = SqlitePool::connect(&config.database.uri).await.unwrap();
+    let shared = "shared response ".repeat(100);
+    for (path, request_body) in [("/a", "first"), ("/b", "second")] {
+        sqlx::query(
+            "INSERT INTO requests (request_id, har, created_at, path, inline_bodies) VALUES (?, ?, 0, ?, 1)",
+        )
+        .bind(Uuid::now_v7().to_string())
+        .bind(
+            common::log(vec![common::entry(
+                path,
+                common::STARTED,
+                Some(request_body),
+                &shared,
+            )])
+            .to_string(),
+        )
+        .bind(path)
+        .execute(&db)
+        .await
+        .unwrap();
+    }
+
+    assert_eq!(park::compress_bodies(&config, true).await.unwrap(), 2);
+    assert_eq!(park::compress_bodies(&config, false).await.unwrap(), 2);
+    assert_eq!(park::compress_bodies(&config, true).await.unwrap(), 0);
+
+    // The shared response body is stored once
+    let bodies: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM bodies")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(bodies, 3);
+    let inline: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM requests WHERE json_extract(har, '$.entries[0].response.content.text') IS NOT NULL",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    assert_eq!(inline, 0);
+
+    let mut exported = Vec::new();
+    park::export(&config, "", false, &mut exported)
+        .await
+        .unwrap();
+    let exported: Value = serde_json::from_slice(&exported).unwrap();
+    let mut bodies: Vec<(&str, &str)> = exported["log"]["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["request"]["postData"]["text"].as_str().unwrap(),
+                entry["response"]["content"]["text"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    bodies.sort();
+    assert_eq!(
+        bodies,
+        [("first", shared.as_str()), ("second", shared.as_str())]
+    );
+}
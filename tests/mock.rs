@@ -0,0 +1,228 @@
+use std::sync::Arc;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, StatusCode};
+use serde_json::{json, Value};
+
+mod common;
+
+use common::TempDir;
+
+/// Mock mode answering from a HAR file holding `entries` with the given `[mock]` settings
+async fn mock_app(
+    dir: &TempDir,
+    entries: Vec<Value>,
+    mock: &str,
+) -> (Arc<park::Config>, park::AppState) {
+    let har_file = dir.path().join("mock.har");
+    std::fs::write(&har_file, common::har_file(entries).to_string()).unwrap();
+
+    let config = common::config(
+        "sqlite::memory:",
+        "",
+        &format!(
+            "[mock]\nenabled = true\nhar_file = \"{}\"\n{mock}",
+            har_file.display()
+        ),
+    );
+    let state = park::app(&config).await.unwrap();
+
+    (Arc::new(config), state)
+}
+
+/// Send a request to mock mode and return the status and body of the response
+async fn send(
+    config: &Arc<park::Config>,
+    state: &park::AppState,
+    method: Method,
+    uri: &str,
+    body: &str,
+) -> (StatusCode, String) {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap();
+    let res = park::mock(config.clone(), state.clone(), req)
+        .await
+        .unwrap();
+    let status = res.status();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn matches_method_and_path() {
+    let dir = TempDir::new("mock-method-path");
+    let entries = vec![
+        common::entry("/a", common::STARTED, None, "get a"),
+        common::entry("/a", common::STARTED, Some("x"), "post a"),
+        common::entry("/b", common::STARTED, None, "get b"),
+    ];
+    let (config, state) = mock_app(&dir, entries, "").await;
+
+    assert_eq!(
+        send(&config, &state, Method::GET, "/a", "").await,
+        (StatusCode::OK, "get a".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::POST, "/a", "x").await,
+        (StatusCode::OK, "post a".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::GET, "/b", "").await,
+        (StatusCode::OK, "get b".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::DELETE, "/a", "").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send(&config, &state, Method::GET, "/c", "").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn matches_query_in_any_order() {
+    let dir = TempDir::new("mock-query");
+    let entries = vec![common::entry("/s?a=1&b=2", common::STARTED, None, "found")];
+    let (config, state) = mock_app(&dir, entries, "").await;
+
+    assert_eq!(
+        send(&config, &state, Method::GET, "/s?b=2&a=1", "").await,
+        (StatusCode::OK, "found".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::GET, "/s?a=1", "").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn matches_body_when_enabled() {
+    let dir = TempDir::new("mock-body");
+    let entries = vec![
+        common::entry("/a", common::STARTED, Some("one"), "1"),
+        common::entry("/a", common::STARTED, Some("two"), "2"),
+    ];
+    let (config, state) = mock_app(&dir, entries, "match_body = true").await;
+
+    assert_eq!(
+        send(&config, &state, Method::POST, "/a", "one").await,
+        (StatusCode::OK, "1".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::POST, "/a", "two").await,
+        (StatusCode::OK, "2".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::POST, "/a", "three").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn falls_through_to_upstream() {
+    let addr = common::upstream().await;
+    let dir = TempDir::new("mock-fallthrough");
+    let har_file = dir.path().join("mock.har");
+    let entries = vec![common::entry("/a", common::STARTED, None, "recorded")];
+    std::fs::write(&har_file, common::har_file(entries).to_string()).unwrap();
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        address = "http://{addr}"
+
+        [mock]
+        enabled = true
+        har_file = "{}"
+        fallthrough = true
+        "#,
+        har_file.display()
+    ))
+    .unwrap();
+    let state = park::app(&config).await.unwrap();
+    let config = Arc::new(config);
+
+    assert_eq!(
+        send(&config, &state, Method::GET, "/a", "").await,
+        (StatusCode::OK, "recorded".to_string())
+    );
+    assert_eq!(
+        send(&config, &state, Method::GET, "/missing?q=1", "").await,
+        (StatusCode::OK, "/missing?q=1".to_string())
+    );
+}
+
+#[tokio::test]
+async fn answers_from_database() {
+    let dir = TempDir::new("mock-database");
+    let config = common::config(&dir.database(), "", "[mock]\nenabled = true");
+    let entries = vec![
+        common::entry("/s?a=1&b=2", common::STARTED, None, "found"),
+        common::entry("/s?a=2", common::STARTED, None, "other"),
+    ];
+    common::import(&config, &dir, common::har_file(entries)).await;
+    let state = park::app(&config).await.unwrap();
+    let config = Arc::new(config);
+
+    let req = Request::builder()
+        .uri("/s?b=2&a=1")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let res = park::mock(config.clone(), state.clone(), req)
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key("x-park-mocked-from"));
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "found");
+}
+
+#[tokio::test]
+async fn answers_with_chrome_http2_entries() {
+    let dir = TempDir::new("mock-chrome");
+
+    // Chrome lists pseudo-headers and stores the decoded body while keeping Content-Encoding
+    let mut decoded = common::entry("/text", common::STARTED, None, "hello");
+    decoded["response"]["httpVersion"] = json!("http/2.0");
+    decoded["response"]["headers"] = json!([
+        { "name": ":status", "value": "200" },
+        { "name": "content-encoding", "value": "gzip" },
+        { "name": "content-type", "value": "text/plain" }
+    ]);
+    // Bodies stored as base64 are the bytes as they were sent
+    let mut raw = common::entry("/raw", common::STARTED, None, "");
+    raw["response"]["headers"] = json!([{ "name": "content-encoding", "value": "identity" }]);
+    raw["response"]["content"]["text"] = json!("aGVsbG8=");
+    raw["response"]["content"]["encoding"] = json!("base64");
+    let (config, state) = mock_app(&dir, vec![decoded, raw], "").await;
+
+    let req = Request::builder()
+        .uri("/text")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let res = park::mock(config.clone(), state.clone(), req)
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(!res.headers().contains_key("content-encoding"));
+    assert_eq!(res.headers()["content-type"], "text/plain");
+    assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+
+    let req = Request::builder()
+        .uri("/raw")
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+    let res = park::mock(config.clone(), state.clone(), req)
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["content-encoding"], "identity");
+    assert_eq!(res.into_body().collect().await.unwrap().to_bytes(), "hello");
+}
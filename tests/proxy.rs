@@ -0,0 +1,570 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{HeaderMap, Request, Response};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use tokio::net::TcpListener;
+
+mod common;
+
+use common::TempDir;
+
+/// Start an upstream server that responds with every header line it received as `name: value`
+async fn headers_upstream() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let lines = req
+                        .headers()
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap()))
+                        .collect::<String>();
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(lines))))
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// Proxy `req` with the given `[server.forwarding]` settings and return the address of the
+/// upstream server along with the headers it received
+async fn forward_headers(forwarding: &str, req: Request<Empty<Bytes>>) -> (SocketAddr, HeaderMap) {
+    let addr = headers_upstream().await;
+
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        address = "http://{addr}"
+
+        [server.forwarding]
+        {forwarding}
+        "#
+    ))
+    .unwrap();
+    let state = park::app(&config).await.unwrap();
+
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    let mut headers = HeaderMap::new();
+    for line in String::from_utf8(body.to_vec()).unwrap().lines() {
+        let (name, value) = line.split_once(": ").unwrap();
+        headers.append(
+            http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+    }
+
+    (addr, headers)
+}
+
+/// The values of every line of a header
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .map(|value| value.to_str().unwrap())
+        .collect()
+}
+
+/// Connection details the listener adds to requests from a downstream client at 192.0.2.1
+fn downstream_connection() -> park::ConnectionInfo {
+    park::ConnectionInfo {
+        remote_addr: "192.0.2.1:50000".parse().unwrap(),
+        tls: false,
+    }
+}
+
+/// Proxy a request for `uri` to an upstream server at `base_path` and return the path and query
+/// the upstream server received
+async fn forward(base_path: &str, uri: &str) -> String {
+    let addr = common::upstream().await;
+
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        address = "http://{addr}{base_path}"
+        "#
+    ))
+    .unwrap();
+    let state = park::app(&config).await.unwrap();
+
+    let req = Request::builder()
+        .uri(uri)
+        .header("host", "localhost")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Proxy a request for `uri` with a route for `path_prefix` that strips the prefix and return the
+/// path the upstream server received. Routed requests are sent to `/routed` and the rest to
+/// `/default`
+async fn route(path_prefix: &str, uri: &str) -> String {
+    let addr = common::upstream().await;
+
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        address = "http://{addr}/default"
+
+        [[routes]]
+        path_prefix = "{path_prefix}"
+        address = "http://{addr}/routed"
+        strip_prefix = true
+        "#
+    ))
+    .unwrap();
+    let state = park::app(&config).await.unwrap();
+
+    let req = Request::builder()
+        .uri(uri)
+        .header("host", "localhost")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn forwards_query_string() {
+    assert_eq!(
+        forward("", "/search?q=a%20b&x=1").await,
+        "/search?q=a%20b&x=1"
+    );
+}
+
+#[tokio::test]
+async fn forwards_encoded_path() {
+    assert_eq!(forward("", "/files/a%2Fb%20c").await, "/files/a%2Fb%20c");
+}
+
+#[tokio::test]
+async fn forwards_empty_query() {
+    assert_eq!(forward("", "/a?").await, "/a?");
+}
+
+#[tokio::test]
+async fn forwards_without_query() {
+    assert_eq!(forward("", "/a").await, "/a");
+}
+
+#[tokio::test]
+async fn keeps_trailing_slash() {
+    assert_eq!(forward("", "/users/").await, "/users/");
+}
+
+#[tokio::test]
+async fn joins_base_path() {
+    assert_eq!(
+        forward("/api/v2", "/users?id=1").await,
+        "/api/v2/users?id=1"
+    );
+}
+
+#[tokio::test]
+async fn joins_base_path_with_trailing_slash() {
+    assert_eq!(forward("/api/v2/", "/users").await, "/api/v2/users");
+}
+
+#[tokio::test]
+async fn joins_root_onto_base_path() {
+    assert_eq!(forward("/api/v2", "/").await, "/api/v2/");
+}
+
+#[tokio::test]
+async fn records_server_latency_as_wait() {
+    let delay = Duration::from_millis(300);
+    let addr = common::delayed_upstream(delay).await;
+
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        address = "http://{addr}"
+        "#
+    ))
+    .unwrap();
+    let (state, writer) = park::app_with_writer(&config).await.unwrap();
+    let db = state.db.clone();
+
+    let req = Request::builder()
+        .method("POST")
+        .uri("/slow")
+        .header("host", "localhost")
+        .body(Full::new(Bytes::from_static(b"request body")))
+        .unwrap();
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+    res.into_body().collect().await.unwrap();
+    writer.join().await.unwrap();
+
+    let (send, wait): (f64, f64) = sqlx::query_as(
+        "SELECT json_extract(har, '$.entries[0].timings.send'), json_extract(har, '$.entries[0].timings.wait') FROM requests",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+
+    let delay = delay.as_millis() as f64;
+    assert!(
+        send < delay / 2.0,
+        "send {send} includes the server latency"
+    );
+    assert!(
+        wait >= delay && wait < delay * 2.0,
+        "wait {wait} does not match the {delay}ms server latency"
+    );
+}
+
+#[tokio::test]
+async fn routes_on_prefix_segment() {
+    assert_eq!(route("/api", "/api/users?id=1").await, "/routed/users?id=1");
+}
+
+#[tokio::test]
+async fn routes_exact_prefix() {
+    assert_eq!(route("/api", "/api").await, "/routed/");
+}
+
+#[tokio::test]
+async fn routes_prefix_with_trailing_slash() {
+    assert_eq!(route("/api/", "/api/users").await, "/routed/users");
+}
+
+#[tokio::test]
+async fn does_not_route_partial_segment() {
+    assert_eq!(route("/api", "/apiv2/users").await, "/default/apiv2/users");
+}
+
+#[tokio::test]
+async fn strips_hop_by_hop_headers() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .header("connection", "keep-alive, x-connection-only")
+        .header("keep-alive", "timeout=5")
+        .header("proxy-authorization", "Basic Zm9vOmJhcg==")
+        .header("te", "trailers")
+        .header("trailer", "expires")
+        .header("x-end-to-end", "kept")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (_, headers) = forward_headers("", req).await;
+
+    assert!(headers.get("keep-alive").is_none());
+    assert!(headers.get("proxy-authorization").is_none());
+    assert!(headers.get("te").is_none());
+    assert_eq!(values(&headers, "trailer"), ["expires"]);
+    assert_eq!(values(&headers, "x-end-to-end"), ["kept"]);
+}
+
+#[tokio::test]
+async fn strips_headers_named_in_connection() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .header("connection", "x-first")
+        .header("connection", "X-Second, close")
+        .header("x-first", "1")
+        .header("x-second", "2")
+        .header("x-third", "3")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (_, headers) = forward_headers("", req).await;
+
+    assert!(headers.get("x-first").is_none());
+    assert!(headers.get("x-second").is_none());
+    assert_eq!(values(&headers, "x-third"), ["3"]);
+}
+
+#[tokio::test]
+async fn rewrites_host() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (addr, headers) = forward_headers("", req).await;
+
+    assert_eq!(values(&headers, "host"), [addr.to_string()]);
+    assert_eq!(values(&headers, "x-forwarded-host"), ["example.test"]);
+}
+
+#[tokio::test]
+async fn preserves_host() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (_, headers) = forward_headers("preserve_host = true", req).await;
+
+    assert_eq!(values(&headers, "host"), ["example.test"]);
+}
+
+#[tokio::test]
+async fn appends_to_forwarding_chain() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .header("x-forwarded-for", "10.0.0.1")
+        .header("x-forwarded-for", "10.0.0.2, 10.0.0.3")
+        .header("via", "1.0 first")
+        .header("via", "1.1 second")
+        .extension(downstream_connection())
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (_, headers) = forward_headers("", req).await;
+
+    assert_eq!(
+        values(&headers, "x-forwarded-for"),
+        ["10.0.0.1, 10.0.0.2, 10.0.0.3, 192.0.2.1"]
+    );
+    assert_eq!(values(&headers, "x-forwarded-proto"), ["http"]);
+    assert_eq!(values(&headers, "via"), ["1.0 first, 1.1 second, 1.1 park"]);
+    assert!(headers.get("forwarded").is_none());
+}
+
+#[tokio::test]
+async fn appends_forwarded() {
+    let req = Request::builder()
+        .uri("/")
+        .header("host", "example.test")
+        .header("forwarded", "for=10.0.0.1")
+        .header("forwarded", "for=10.0.0.2")
+        .extension(downstream_connection())
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let (_, headers) = forward_headers(
+        r#"
+        forwarded = true
+        x_forwarded = false
+        via = false
+        "#,
+        req,
+    )
+    .await;
+
+    assert_eq!(
+        values(&headers, "forwarded"),
+        [r#"for=10.0.0.1, for=10.0.0.2, for=192.0.2.1;host="example.test";proto=http"#]
+    );
+    assert!(headers.get("x-forwarded-for").is_none());
+    assert!(headers.get("via").is_none());
+}
+
+/// A config with the given `[server]` settings
+fn server_config(server: &str) -> park::Config {
+    toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "sqlite::memory:"
+
+        [server]
+        {server}
+        "#
+    ))
+    .unwrap()
+}
+
+/// Send a CONNECT request for a listening socket with the given `[server]` settings and return
+/// the response status
+async fn connect(server: &str) -> http::StatusCode {
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+
+    let config = server_config(server);
+    let state = park::app(&config).await.unwrap();
+
+    let req = Request::builder()
+        .method("CONNECT")
+        .uri(target_addr.to_string())
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+
+    res.status()
+}
+
+#[tokio::test]
+async fn allows_any_tunnel_with_upstream_address() {
+    assert_eq!(
+        connect(r#"address = "http://127.0.0.1:1""#).await,
+        http::StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn refuses_tunnels_in_forward_proxy_mode_by_default() {
+    assert_eq!(connect("").await, http::StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn restricts_tunnels_to_allowed_destinations() {
+    let server = r#"
+        [server.destinations]
+        allowed_hosts = ["127.0.0.1"]
+        allowed_ports = []
+        "#;
+    assert_eq!(connect(server).await, http::StatusCode::FORBIDDEN);
+
+    let server = r#"
+        address = "http://127.0.0.1:1"
+
+        [server.destinations]
+        allowed_hosts = ["localhost"]
+        "#;
+    assert_eq!(connect(server).await, http::StatusCode::FORBIDDEN);
+}
+
+/// Send `req` through a forward proxy allowed to reach 127.0.0.1 and return the response status
+async fn forward_proxy(req: Request<Empty<Bytes>>) -> http::StatusCode {
+    let config = server_config(
+        r#"
+        [server.destinations]
+        allowed_hosts = ["127.0.0.1"]
+        "#,
+    );
+    let state = park::app(&config).await.unwrap();
+
+    park::proxy(Arc::new(config), state, req)
+        .await
+        .unwrap()
+        .status()
+}
+
+#[test]
+fn wildcard_hosts_do_not_match_the_domain_itself() {
+    let config = server_config(
+        r#"
+        [server.destinations]
+        allowed_hosts = ["*.example.com"]
+        "#,
+    );
+
+    assert!(config.server.allows_destination("api.example.com"));
+    assert!(config.server.allows_destination("a.b.EXAMPLE.com"));
+    assert!(!config.server.allows_destination("example.com"));
+    assert!(!config.server.allows_destination("badexample.com"));
+}
+
+#[test]
+fn denied_hosts_win_over_allowed_hosts() {
+    let config = server_config(
+        r#"
+        [server.destinations]
+        allowed_hosts = ["*"]
+        denied_hosts = ["admin.example.com", "*.internal"]
+        "#,
+    );
+
+    assert!(config.server.allows_destination("example.com"));
+    assert!(!config.server.allows_destination("admin.example.com"));
+    assert!(!config.server.allows_destination("db.internal"));
+}
+
+#[tokio::test]
+async fn refuses_every_destination_in_forward_proxy_mode_by_default() {
+    let config = server_config("");
+    assert!(!config.server.allows_destination("example.com"));
+    assert!(!config.server.allows_destination("127.0.0.1"));
+
+    let addr = common::upstream().await;
+    let state = park::app(&config).await.unwrap();
+    let req = Request::builder()
+        .uri(format!("http://{addr}/"))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let res = park::proxy(Arc::new(config), state, req).await.unwrap();
+
+    assert_eq!(res.status(), http::StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn detects_forwarding_loops() {
+    let addr = common::upstream().await;
+
+    let req = Request::builder()
+        .uri(format!("http://{addr}/"))
+        .header("via", "1.1 other")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    assert_eq!(forward_proxy(req).await, http::StatusCode::OK);
+
+    let req = Request::builder()
+        .uri(format!("http://{addr}/"))
+        .header("via", "1.0 other, 1.1 park")
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    assert_eq!(forward_proxy(req).await, http::StatusCode::LOOP_DETECTED);
+}
+
+#[tokio::test]
+async fn replays_http2_entries_with_pseudo_headers() {
+    let addr = common::upstream().await;
+    let dir = TempDir::new("replay-http2");
+    let config: park::Config = toml::from_str(&format!(
+        r#"
+        [database]
+        uri = "{}"
+
+        [server]
+        address = "http://{addr}"
+        "#,
+        dir.database()
+    ))
+    .unwrap();
+
+    // Chrome lists the pseudo-headers of HTTP/2 requests along with the real ones
+    let mut entry = common::entry("/h2?q=1", common::STARTED, None, "/h2?q=1");
+    entry["request"]["url"] = json!(format!("http://{addr}/h2?q=1"));
+    entry["request"]["httpVersion"] = json!("http/2.0");
+    entry["request"]["headers"] = json!([
+        { "name": ":authority", "value": addr.to_string() },
+        { "name": ":method", "value": "GET" },
+        { "name": ":path", "value": "/h2?q=1" },
+        { "name": ":scheme", "value": "http" },
+        { "name": "accept", "value": "*/*" }
+    ]);
+    common::import(&config, &dir, common::har_file(vec![entry])).await;
+
+    let report = park::replay(config, park::ReplayOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(report.failed, 0, "{:?}", report.results);
+    assert_eq!(report.unchanged, 1);
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+mod common;
+
+use common::TempDir;
+
+/// The bytes `max_bytes` limits
+async fn stored_bytes(config: &park::Config) -> i64 {
+    let db = SqlitePool::connect(&config.database.uri).await.unwrap();
+    sqlx::query_scalar(
+        "SELECT (SELECT sum(octet_length(har)) FROM requests) + (SELECT sum(length(data)) FROM bodies)",
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap()
+}
+
+/// Paths of the stored requests from oldest to newest
+async fn stored_paths(config: &park::Config) -> Vec<String> {
+    let db = SqlitePool::connect(&config.database.uri).await.unwrap();
+    sqlx::query_scalar("SELECT path FROM requests ORDER BY created_at, request_id")
+        .fetch_all(&db)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn max_age_counts_from_import() {
+    let dir = TempDir::new("max-age");
+    let config = common::config(&dir.database(), "max_age = 3600\nprune_interval = 1", "");
+
+    let old = common::entry("/old", "2020-01-01T00:00:00.000Z", None, "");
+    common::import(&config, &dir, common::har_file(vec![old])).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    assert_eq!(stored_paths(&config).await, ["/old"]);
+}
+
+#[tokio::test]
+async fn max_bytes_keeps_requests_sharing_a_body() {
+    let dir = TempDir::new("max-bytes");
+    let config = common::config(&dir.database(), "", "");
+
+    // Every request refers to the same stored body, so deleting one only frees its HAR
+    let body: String = (0..4000)
+        .map(|_| Uuid::now_v7().simple().to_string())
+        .collect();
+    for n in 0..10 {
+        let started = format!("2024-01-01T00:00:{:02}.000Z", n);
+        let entry = common::entry(&format!("/{n}"), &started, None, &body);
+        common::import(&config, &dir, common::har_file(vec![entry])).await;
+    }
+    let used = stored_bytes(&config).await;
+
+    let max_bytes = used - 1000;
+    let config = common::config(
+        &dir.database(),
+        &format!(
+            "max_bytes = {max_bytes}
+prune_interval = 1"
+        ),
+        "",
+    );
+    let _state = park::app(&config).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+
+    let paths = stored_paths(&config).await;
+    assert!(stored_bytes(&config).await <= max_bytes);
+    assert!(paths.len() >= 5, "kept {:?}", paths);
+    assert_eq!(paths.last().unwrap(), "/9");
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+use std::time::Duration;
+
+use sqlx::SqlitePool;
+
+mod common;
+
+use common::TempDir;
+
+/// A recording of a request for `/{n}`
+fn recording(n: usize) -> park::Recording {
+    common::recording(common::entry(&format!("/{n}"), common::STARTED, None, ""))
+}
+
+async fn app(overflow: &str, capacity: usize, spill_file: &Path) -> (park::AppState, park::Writer) {
+    let config = common::config(
+        "sqlite::memory:",
+        "",
+        &format!(
+            r#"
+            [queue]
+            capacity = {capacity}
+            overflow = "{overflow}"
+            spill_file = "{}"
+            "#,
+            spill_file.display()
+        ),
+    );
+
+    park::app_with_writer(&config).await.unwrap()
+}
+
+/// Paths of the stored requests from oldest to newest
+async fn stored_paths(db: &SqlitePool) -> Vec<String> {
+    sqlx::query_scalar("SELECT path FROM requests ORDER BY request_id")
+        .fetch_all(db)
+        .await
+        .unwrap()
+}
+
+/// Queue `count` recordings without yielding, so the writer cannot take any of them until the
+/// last one has been queued unless the queue blocks
+async fn send(queue: &park::HarQueue, count: usize) {
+    for n in 0..count {
+        queue.send(recording(n)).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn block_waits_for_room() {
+    let dir = TempDir::new("block");
+    let spill_file = dir.path().join("spill.jsonl");
+    let (state, writer) = app("block", 2, &spill_file).await;
+    let db = state.db.clone();
+
+    send(&state.har_queue, 5).await;
+    let stats = state.har_queue.stats();
+    drop(state);
+    writer.join().await.unwrap();
+
+    assert_eq!(stats.dropped, 0);
+    assert_eq!(stored_paths(&db).await, ["/0", "/1", "/2", "/3", "/4"]);
+}
+
+#[tokio::test]
+async fn drop_oldest_keeps_newest() {
+    let dir = TempDir::new("drop-oldest");
+    let spill_file = dir.path().join("spill.jsonl");
+    let (state, writer) = app("drop-oldest", 2, &spill_file).await;
+    let db = state.db.clone();
+
+    send(&state.har_queue, 5).await;
+    let stats = state.har_queue.stats();
+    drop(state);
+    writer.join().await.unwrap();
+
+    assert_eq!(stats.dropped, 3);
+    assert_eq!(stored_paths(&db).await, ["/3", "/4"]);
+}
+
+#[tokio::test]
+async fn drop_newest_keeps_oldest() {
+    let dir = TempDir::new("drop-newest");
+    let spill_file = dir.path().join("spill.jsonl");
+    let (state, writer) = app("drop-newest", 2, &spill_file).await;
+    let db = state.db.clone();
+
+    send(&state.har_queue, 5).await;
+    let stats = state.har_queue.stats();
+    drop(state);
+    writer.join().await.unwrap();
+
+    assert_eq!(stats.dropped, 3);
+    assert_eq!(stored_paths(&db).await, ["/0", "/1"]);
+}
+
+#[tokio::test]
+async fn spill_writes_overflow_later() {
+    let dir = TempDir::new("spill");
+    let spill_file = dir.path().join("spill.jsonl");
+    let (state, writer) = app("spill", 2, &spill_file).await;
+    let db = state.db.clone();
+    let queue = state.har_queue.clone();
+    drop(state);
+
+    // Spilling writes to the file in the background, so the writer may take a batch while
+    // recordings are being spilled and only some of the overflow ends up in the file
+    send(&queue, 5).await;
+    let stats = queue.stats();
+    drop(queue);
+    writer.join().await.unwrap();
+
+    assert!(stats.spilled > 0);
+    assert_eq!(stats.dropped, 0);
+    let mut paths = stored_paths(&db).await;
+    paths.sort();
+    assert_eq!(paths, ["/0", "/1", "/2", "/3", "/4"]);
+    assert!(!spill_file.exists());
+}
+
+#[tokio::test]
+async fn replays_spill_file_on_start() {
+    let dir = TempDir::new("replay");
+    let spill_file = dir.path().join("spill.jsonl");
+    let mut lines = String::new();
+    for n in 0..3 {
+        lines.push_str(&serde_json::to_string(&recording(n)).unwrap());
+        lines.push('\n');
+        if n == 1 {
+            lines.push_str("{\"not\": \"a recording\"}\n");
+        }
+    }
+    std::fs::write(&spill_file, lines).unwrap();
+
+    let (state, writer) = app("spill", 2, &spill_file).await;
+    let db = state.db.clone();
+    let queue = state.har_queue.clone();
+    drop(state);
+
+    // The spill file is replayed before anything is queued
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while queue.stats().replayed < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let stats = queue.stats();
+    drop(queue);
+    writer.join().await.unwrap();
+
+    assert_eq!(stats.replayed, 3);
+    assert_eq!(stats.dropped, 1);
+    assert_eq!(stored_paths(&db).await, ["/0", "/1", "/2"]);
+    assert!(!spill_file.exists());
+}
+
+#[tokio::test]
+async fn replays_leftover_replay_file_first() {
+    let dir = TempDir::new("replay-leftover");
+    let spill_file = dir.path().join("spill.jsonl");
+    let replay_file = dir.path().join("spill.jsonl.replay");
+    let line = |n| serde_json::to_string(&recording(n)).unwrap() + "\n";
+    std::fs::write(&replay_file, line(0) + &line(1)).unwrap();
+    std::fs::write(&spill_file, line(2)).unwrap();
+
+    let (state, writer) = app("spill", 2, &spill_file).await;
+    let db = state.db.clone();
+    let queue = state.har_queue.clone();
+    drop(state);
+    drop(queue);
+    writer.join().await.unwrap();
+
+    assert_eq!(stored_paths(&db).await, ["/0", "/1", "/2"]);
+    assert!(!spill_file.exists());
+    assert!(!replay_file.exists());
+}